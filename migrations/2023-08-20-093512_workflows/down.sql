@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE events DROP CONSTRAINT fk_workflow_uid;
+ALTER TABLE events DROP COLUMN workflow_uid;
+
+DROP TABLE IF EXISTS workflows;
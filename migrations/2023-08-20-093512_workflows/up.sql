@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS workflows (
+    uid             SERIAL PRIMARY KEY,
+    name            VARCHAR,
+    description     VARCHAR,
+    created_at      TIMESTAMP NOT NULL DEFAULT NOW(),
+    deleted_at      TIMESTAMP
+);
+
+-- Nullable since events added before this migration don't belong to any workflow
+ALTER TABLE events ADD COLUMN workflow_uid INTEGER;
+ALTER TABLE events ADD CONSTRAINT fk_workflow_uid
+    FOREIGN KEY(workflow_uid)
+        REFERENCES workflows(uid) ON DELETE CASCADE ON UPDATE CASCADE;
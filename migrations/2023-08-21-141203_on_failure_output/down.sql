@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE tasks DROP COLUMN on_failure_status;
+ALTER TABLE tasks DROP COLUMN on_failure_stdout;
+ALTER TABLE tasks DROP COLUMN on_failure_stderr;
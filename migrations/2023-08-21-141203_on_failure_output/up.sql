@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE tasks ADD COLUMN on_failure_status VARCHAR;
+ALTER TABLE tasks ADD COLUMN on_failure_stdout TEXT;
+ALTER TABLE tasks ADD COLUMN on_failure_stderr TEXT;
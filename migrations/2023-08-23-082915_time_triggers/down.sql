@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE events DROP COLUMN schedule;
+ALTER TABLE events DROP COLUMN timezone;
+ALTER TABLE events DROP COLUMN every_seconds;
+ALTER TABLE events DROP COLUMN next_fire_at;
+
+DELETE FROM events WHERE trigger IS NULL;
+ALTER TABLE events ALTER COLUMN trigger SET NOT NULL;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- Time based events don't have a trigger script
+ALTER TABLE events ALTER COLUMN trigger DROP NOT NULL;
+
+ALTER TABLE events ADD COLUMN schedule VARCHAR;
+ALTER TABLE events ADD COLUMN timezone VARCHAR;
+ALTER TABLE events ADD COLUMN every_seconds BIGINT;
+-- In UTC, so it doesn't depend on the time zone of the engine or the database
+ALTER TABLE events ADD COLUMN next_fire_at TIMESTAMP;
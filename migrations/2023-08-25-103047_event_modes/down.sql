@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS event_runs;
+
+ALTER TABLE events DROP COLUMN mode;
+ALTER TABLE events DROP COLUMN cooldown_seconds;
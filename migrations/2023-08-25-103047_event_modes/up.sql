@@ -0,0 +1,18 @@
+-- Your SQL goes here
+ALTER TABLE events ADD COLUMN mode VARCHAR NOT NULL DEFAULT 'once';
+ALTER TABLE events ADD COLUMN cooldown_seconds BIGINT;
+-- Time based events kept firing before modes existed
+UPDATE events SET mode = 'recurring' WHERE schedule IS NOT NULL OR every_seconds IS NOT NULL;
+
+-- One row per fire of an event
+CREATE TABLE IF NOT EXISTS event_runs (
+    uid             SERIAL PRIMARY KEY,
+    event_uid       INTEGER NOT NULL,
+    status          VARCHAR NOT NULL,
+    started_at      TIMESTAMP NOT NULL DEFAULT NOW(),
+    stdout          TEXT,
+    stderr          TEXT,
+    CONSTRAINT fk_event_uid
+        FOREIGN KEY(event_uid)
+            REFERENCES events(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE tasks ADD COLUMN completed_at TIMESTAMP;
+ALTER TABLE tasks ADD COLUMN stdout TEXT;
+ALTER TABLE tasks ADD COLUMN stderr TEXT;
+ALTER TABLE tasks ADD COLUMN on_failure_status VARCHAR;
+ALTER TABLE tasks ADD COLUMN on_failure_stdout TEXT;
+ALTER TABLE tasks ADD COLUMN on_failure_stderr TEXT;
+
+DROP TABLE IF EXISTS task_runs;
@@ -0,0 +1,41 @@
+-- Your SQL goes here
+-- One row per execution of a task, run_uid groups the task runs of one event fire
+CREATE TABLE IF NOT EXISTS task_runs (
+    uid                 SERIAL PRIMARY KEY,
+    run_uid             INTEGER,
+    task_uid            INTEGER NOT NULL,
+    status              VARCHAR NOT NULL,
+    created_at          TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at          TIMESTAMP NOT NULL DEFAULT NOW(),
+    started_at          TIMESTAMP,
+    completed_at        TIMESTAMP,
+    stdout              TEXT,
+    stderr              TEXT,
+    on_failure_status   VARCHAR,
+    on_failure_stdout   TEXT,
+    on_failure_stderr   TEXT,
+    CONSTRAINT fk_run_uid
+        FOREIGN KEY(run_uid)
+            REFERENCES event_runs(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT fk_task_uid
+        FOREIGN KEY(task_uid)
+            REFERENCES tasks(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- Keep the outcome of executions that happened before runs existed, they don't belong to any run
+INSERT INTO task_runs (task_uid, status, created_at, updated_at, started_at, completed_at,
+                       stdout, stderr, on_failure_status, on_failure_stdout, on_failure_stderr)
+SELECT uid, status, updated_at, updated_at, updated_at, completed_at,
+       stdout, stderr, on_failure_status, on_failure_stdout, on_failure_stderr
+FROM tasks
+WHERE status NOT IN ('Created', 'Paused');
+
+-- The status of a task is now only whether it is paused or not
+UPDATE tasks SET status = 'Created' WHERE status <> 'Paused';
+
+ALTER TABLE tasks DROP COLUMN completed_at;
+ALTER TABLE tasks DROP COLUMN stdout;
+ALTER TABLE tasks DROP COLUMN stderr;
+ALTER TABLE tasks DROP COLUMN on_failure_status;
+ALTER TABLE tasks DROP COLUMN on_failure_stdout;
+ALTER TABLE tasks DROP COLUMN on_failure_stderr;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- A task only starts once all of its dependencies have completed in the same run
+CREATE TABLE IF NOT EXISTS task_dependencies (
+    task_uid        INTEGER NOT NULL,
+    dependency_uid  INTEGER NOT NULL,
+    PRIMARY KEY (task_uid, dependency_uid),
+    CONSTRAINT fk_task_uid
+        FOREIGN KEY(task_uid)
+            REFERENCES tasks(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT fk_dependency_uid
+        FOREIGN KEY(dependency_uid)
+            REFERENCES tasks(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE tasks DROP COLUMN timeout_seconds;
+ALTER TABLE events DROP COLUMN timeout_seconds;
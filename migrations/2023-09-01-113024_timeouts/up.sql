@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE events ADD COLUMN timeout_seconds BIGINT;
+ALTER TABLE tasks ADD COLUMN timeout_seconds BIGINT;
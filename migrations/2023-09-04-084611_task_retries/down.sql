@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE task_runs DROP COLUMN attempt;
+ALTER TABLE tasks DROP COLUMN backoff;
+ALTER TABLE tasks DROP COLUMN retry_delay_seconds;
+ALTER TABLE tasks DROP COLUMN retries;
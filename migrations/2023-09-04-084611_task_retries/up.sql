@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE tasks ADD COLUMN retries INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tasks ADD COLUMN retry_delay_seconds BIGINT;
+ALTER TABLE tasks ADD COLUMN backoff VARCHAR NOT NULL DEFAULT 'fixed';
+-- Attempts are counted from 1, a task run is attempted at most retries + 1 times
+ALTER TABLE task_runs ADD COLUMN attempt INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE events DROP COLUMN next_poll_at;
+ALTER TABLE events DROP COLUMN attempts;
+ALTER TABLE events DROP COLUMN backoff;
+ALTER TABLE events DROP COLUMN max_attempts;
+ALTER TABLE events DROP COLUMN poll_interval_seconds;
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE events ADD COLUMN poll_interval_seconds BIGINT;
+ALTER TABLE events ADD COLUMN max_attempts INTEGER;
+ALTER TABLE events ADD COLUMN backoff VARCHAR NOT NULL DEFAULT 'fixed';
+-- Failed trigger runs since the last successful one
+ALTER TABLE events ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE events ADD COLUMN next_poll_at TIMESTAMP;
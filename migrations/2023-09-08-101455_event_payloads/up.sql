@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- What the trigger reported when the event fired, handed to the tasks of the run
+ALTER TABLE event_runs ADD COLUMN payload TEXT;
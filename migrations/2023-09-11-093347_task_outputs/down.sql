@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE task_runs DROP COLUMN outputs;
+ALTER TABLE tasks DROP COLUMN args;
+ALTER TABLE tasks DROP COLUMN env;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- Environment variables and arguments of the task, they can reference outputs of other tasks
+ALTER TABLE tasks ADD COLUMN env JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE tasks ADD COLUMN args JSONB NOT NULL DEFAULT '[]';
+-- Key value pairs the task wrote to its output file
+ALTER TABLE task_runs ADD COLUMN outputs JSONB;
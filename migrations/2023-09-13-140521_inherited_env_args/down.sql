@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE events DROP COLUMN args;
+ALTER TABLE events DROP COLUMN env;
+ALTER TABLE workflows DROP COLUMN args;
+ALTER TABLE workflows DROP COLUMN env;
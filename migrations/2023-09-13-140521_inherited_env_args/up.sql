@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- Tasks inherit the environment variables and arguments of their event and workflow
+ALTER TABLE workflows ADD COLUMN env JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE workflows ADD COLUMN args JSONB NOT NULL DEFAULT '[]';
+ALTER TABLE events ADD COLUMN env JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE events ADD COLUMN args JSONB NOT NULL DEFAULT '[]';
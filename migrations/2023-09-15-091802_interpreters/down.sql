@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE events DROP COLUMN working_directory;
+ALTER TABLE events DROP COLUMN run;
+ALTER TABLE events DROP COLUMN command;
+ALTER TABLE events DROP COLUMN interpreter;
+ALTER TABLE tasks DROP COLUMN working_directory;
+ALTER TABLE tasks DROP COLUMN run;
+ALTER TABLE tasks DROP COLUMN command;
+ALTER TABLE tasks DROP COLUMN interpreter;
+DELETE FROM tasks WHERE path IS NULL;
+ALTER TABLE tasks ALTER COLUMN path SET NOT NULL;
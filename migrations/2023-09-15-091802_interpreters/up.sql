@@ -0,0 +1,12 @@
+-- Your SQL goes here
+-- Besides a script file, a task or trigger can run an executable or an inline script.
+-- Those run from the directory of the workflow file
+ALTER TABLE tasks ALTER COLUMN path DROP NOT NULL;
+ALTER TABLE tasks ADD COLUMN interpreter VARCHAR;
+ALTER TABLE tasks ADD COLUMN command VARCHAR;
+ALTER TABLE tasks ADD COLUMN run TEXT;
+ALTER TABLE tasks ADD COLUMN working_directory VARCHAR;
+ALTER TABLE events ADD COLUMN interpreter VARCHAR;
+ALTER TABLE events ADD COLUMN command VARCHAR;
+ALTER TABLE events ADD COLUMN run TEXT;
+ALTER TABLE events ADD COLUMN working_directory VARCHAR;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX workflows_active_name;
+DROP INDEX workflows_name_version;
+ALTER TABLE workflows DROP COLUMN active;
+ALTER TABLE workflows DROP COLUMN version;
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+-- Workflows are identified by their name, adding a workflow again adds a new version of it.
+-- Only the events of the active version are polled
+ALTER TABLE workflows ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE workflows ADD COLUMN active BOOLEAN NOT NULL DEFAULT TRUE;
+-- Workflows added more than once become versions of the same workflow, the latest one is active
+UPDATE workflows
+SET version = versions.version, active = versions.version = versions.latest_version
+FROM (
+    SELECT uid,
+           ROW_NUMBER() OVER (PARTITION BY name ORDER BY uid) AS version,
+           COUNT(*) OVER (PARTITION BY name) AS latest_version
+    FROM workflows
+    WHERE name IS NOT NULL
+) AS versions
+WHERE workflows.uid = versions.uid;
+CREATE UNIQUE INDEX workflows_name_version ON workflows (name, version);
+CREATE UNIQUE INDEX workflows_active_name ON workflows (name) WHERE active;
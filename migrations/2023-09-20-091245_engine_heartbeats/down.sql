@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE engines DROP COLUMN event_heartbeat_at;
+ALTER TABLE engines DROP COLUMN last_heartbeat_at;
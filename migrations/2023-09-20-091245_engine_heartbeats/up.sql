@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE engines ADD COLUMN last_heartbeat_at TIMESTAMP;
+ALTER TABLE engines ADD COLUMN event_heartbeat_at TIMESTAMP;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX events_owner_engine_uid;
+ALTER TABLE events DROP COLUMN lease_expires_at;
+ALTER TABLE events DROP COLUMN owner_engine_uid;
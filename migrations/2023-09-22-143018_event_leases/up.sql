@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE events ADD COLUMN owner_engine_uid INTEGER REFERENCES engines(uid) ON DELETE SET NULL;
+ALTER TABLE events ADD COLUMN lease_expires_at TIMESTAMP;
+CREATE INDEX events_owner_engine_uid ON events (owner_engine_uid);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE task_runs DROP COLUMN picked_up_at;
+ALTER TABLE task_runs DROP COLUMN engine_uid;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE task_runs ADD COLUMN engine_uid INTEGER REFERENCES engines(uid) ON DELETE SET NULL;
+ALTER TABLE task_runs ADD COLUMN picked_up_at TIMESTAMP;
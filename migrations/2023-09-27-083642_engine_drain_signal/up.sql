@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE engines ADD COLUMN drain_signal BOOLEAN NOT NULL DEFAULT false;
@@ -8,17 +8,28 @@ use serde_json::Value;
 use std::env;
 use std::fs::File;
 use std::process::Command;
-use tracing::field;
+use workflow::control::{
+    control_event, control_task, control_workflow, delete_workflow, resolve_event_uid,
+    resolve_task_uid, resolve_workflow_uid, restore_workflow, rollback_workflow, workflow_history,
+    ControlAction,
+};
 use workflow::engine::{create_new_engine_entry, handle_stop, run_event_process};
-use workflow::engine::{run_task_process, update_engine_status};
-use workflow::models::{Engine, EngineStatus, Event, Task};
-use workflow::parser::process_yaml_file;
+use workflow::engine::{
+    engine_liveness, run_task_process, seconds_since_heartbeat, update_engine_status,
+};
+use workflow::engine::{
+    engine_processes_stopped, reset_engine, resolve_engine_uid, wait_for_engines_to_stop,
+};
+use workflow::export::export_workflow;
+use workflow::models::{Engine, EngineStatus, Event, EventRun, Task, TaskRun, Workflow};
+use workflow::parser::{
+    process_workflow_file, validate_workflow_file, workflow_schema, WorkflowFormat,
+};
 use workflow::utils::establish_pg_connection;
 use workflow::utils::run_migrations;
 
 const PRETTY_TABLE_MAX_CELL_LEN: usize = 50;
 const ENGINE_NAME: &str = "workflow-engine";
-const ENGINE_IP_ADDRESS: &str = "0.0.0.0";
 
 // #[clap(about = "A tool to command workflow engine", author, version)]
 #[derive(Parser)]
@@ -41,27 +52,84 @@ enum Commands {
     StartEventProcess {
         engine_uid: i32,
     },
-    Stop {},
-    /// Adds workflow to the queue
+    // Stops every engine, or the one given with --engine. With --drain, engines take no new
+    // tasks and stop once their running tasks are done.
+    Stop {
+        #[clap(long, value_name = "UID|NAME")]
+        engine: Option<String>,
+        #[clap(long)]
+        drain: bool,
+    },
+    // Stops an engine and starts its processes again on this machine, draining it first with --drain.
+    // An engine whose processes didn't report stopping, such as a lost one, needs --force
+    Restart {
+        #[clap(long, value_name = "UID|NAME")]
+        engine: String,
+        #[clap(long)]
+        drain: bool,
+        #[clap(long)]
+        force: bool,
+    },
+    /// Adds workflow to the queue, from a yaml, json or toml file or from stdin with `-`
     Add {
         file_path: String,
+        // Format of the file when its extension doesn't tell, yaml, json or toml
+        #[clap(long)]
+        format: Option<WorkflowFormat>,
+    },
+    // Checks a workflow file without adding it
+    Validate {
+        file_path: String,
+        #[clap(long)]
+        format: Option<WorkflowFormat>,
+    },
+    // Soft deletes a workflow with all of its versions, or removes it for good with --purge
+    Delete {
+        target: String,
+        #[clap(long)]
+        purge: bool,
+    },
+    // Brings back a soft deleted workflow
+    Restore {
+        target: String,
+    },
+    // Lists every version of a workflow
+    History {
+        name: String,
     },
+    // Adds an older version of a workflow back as its latest version
+    Rollback {
+        name: String,
+        #[clap(value_name = "VERSION")]
+        workflow_version: i32,
+    },
+    // Prints the definition file of a workflow rebuilt from the database
+    Export {
+        uid: i32,
+        #[clap(long, default_value = "yaml")]
+        format: WorkflowFormat,
+    },
+    // Prints the JSON Schema of workflow files
+    Schema {},
     // Shows the status of a task
     Show {
         #[clap(subcommand)]
         subcommand: ShowSubcommands,
     },
-    // Pauses the task
+    // Pauses a task, an event or a workflow, such as `pause task <uid|name>`
     Pause {
-        task_name: String,
+        #[clap(subcommand)]
+        subcommand: ControlSubcommands,
     },
-    // Continues the task
+    // Continues a paused task, event or workflow
     Continue {
-        task_name: String,
+        #[clap(subcommand)]
+        subcommand: ControlSubcommands,
     },
-    // Aborts the task
+    // Aborts a task, an event or a workflow, running tasks get killed
     Abort {
-        task_name: String,
+        #[clap(subcommand)]
+        subcommand: ControlSubcommands,
     },
     // Lists that takes subcommands, such as `list tasks` or `list events` or `list engines` or `list workflows` or `list all`
     List {
@@ -80,6 +148,8 @@ enum ListSubcommands {
     Workflows {},
     // Lists all engines
     Engines {},
+    // Lists all runs, a run is everything triggered by one fire of an event
+    Runs {},
     // Lists all
     All {},
 }
@@ -94,6 +164,26 @@ enum ShowSubcommands {
     Workflow { uid: i32 },
     // Lists all engines
     Engine { uid: i32 },
+    // Shows a run and the task runs it triggered
+    Run { uid: i32 },
+}
+
+#[derive(Subcommand)]
+enum ControlSubcommands {
+    // Controls a single task
+    Task { target: String },
+    // Controls an event and all of its tasks
+    Event { target: String },
+    // Controls all events and tasks of a workflow
+    Workflow { target: String },
+}
+
+// An engine as listed, along with whether it is still alive
+#[derive(serde::Serialize)]
+struct EngineWithLiveness {
+    #[serde(flatten)]
+    engine: Engine,
+    liveness: String,
 }
 
 #[derive(PartialEq)]
@@ -186,38 +276,132 @@ pub fn cli() {
                 std::process::exit(1);
             };
         }
-        Commands::Stop {} => {
-            println!("Stopping the engine");
-            //todo: handle stop for multiple engines
-            if let Err(e) = handle_stop() {
+        Commands::Stop { engine, drain } => {
+            if let Err(e) = process_stop_command(engine.as_deref(), *drain) {
                 println!("Failed to stop the engine, {}", e);
                 std::process::exit(1);
             };
         }
-        Commands::Add { file_path } => {
+        Commands::Restart {
+            engine,
+            drain,
+            force,
+        } => {
+            if let Err(e) = process_restart_command(engine, *drain, *force) {
+                println!("Failed to restart the engine, {}", e);
+                std::process::exit(1);
+            };
+        }
+        Commands::Add { file_path, format } => {
             println!("Adding file: {}", file_path);
-            if let Err(e) = process_yaml_file(file_path.to_string()) {
+            if let Err(e) = process_workflow_file(file_path, *format) {
                 println!("Failed to add file, {}", e);
                 std::process::exit(1);
             }
         }
+        Commands::Validate { file_path, format } => {
+            if let Err(e) = validate_workflow_file(file_path, *format) {
+                println!("Validation failed, {}", e);
+                std::process::exit(1);
+            }
+            println!("{} is valid", file_path);
+        }
+        Commands::Delete { target, purge } => {
+            let conn = &mut establish_pg_connection();
+            let result = resolve_workflow_uid(conn, target)
+                .and_then(|workflow_uid| delete_workflow(conn, workflow_uid, *purge));
+            if let Err(e) = result {
+                println!("Failed to delete workflow, {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Restore { target } => {
+            let conn = &mut establish_pg_connection();
+            let result = resolve_workflow_uid(conn, target)
+                .and_then(|workflow_uid| restore_workflow(conn, workflow_uid));
+            if let Err(e) = result {
+                println!("Failed to restore workflow, {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::History { name } => {
+            match workflow_history(&mut establish_pg_connection(), name) {
+                Ok(versions) => {
+                    if let Err(e) = list_items(versions) {
+                        println!("Failed to list versions, {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    println!("Failed to show history, {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Rollback {
+            name,
+            workflow_version,
+        } => match rollback_workflow(&mut establish_pg_connection(), name, *workflow_version) {
+            Ok((workflow_uid, new_version)) => println!(
+                "Rolled back workflow '{}' to version {}, as version {} with uid: {}",
+                name, workflow_version, new_version, workflow_uid
+            ),
+            Err(e) => {
+                println!("Failed to roll back, {}", e);
+                std::process::exit(1);
+            }
+        },
+        Commands::Export { uid, format } => {
+            match export_workflow(&mut establish_pg_connection(), *uid, *format) {
+                Ok(definition) => print!("{}", definition),
+                Err(e) => {
+                    println!("Failed to export workflow {}, {}", uid, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Schema {} => match workflow_schema() {
+            Ok(schema) => println!("{}", schema),
+            Err(e) => {
+                println!("Failed to generate the schema, {}", e);
+                std::process::exit(1);
+            }
+        },
         Commands::Show { subcommand } => {
             if let Err(e) = process_show_subcommands(&mut establish_pg_connection(), subcommand) {
                 println!("Failed to show, {}", e);
                 std::process::exit(1);
             };
         }
-        Commands::Pause { task_name } => {
-            println!("Continuing task: {}", task_name);
-            todo!()
+        Commands::Pause { subcommand } => {
+            if let Err(e) = process_control_subcommands(
+                &mut establish_pg_connection(),
+                subcommand,
+                ControlAction::Pause,
+            ) {
+                println!("Failed to pause, {}", e);
+                std::process::exit(1);
+            };
         }
-        Commands::Continue { task_name } => {
-            println!("Continuing task: {}", task_name);
-            todo!()
+        Commands::Continue { subcommand } => {
+            if let Err(e) = process_control_subcommands(
+                &mut establish_pg_connection(),
+                subcommand,
+                ControlAction::Continue,
+            ) {
+                println!("Failed to continue, {}", e);
+                std::process::exit(1);
+            };
         }
-        Commands::Abort { task_name } => {
-            println!("Aborting task: {}", task_name);
-            todo!()
+        Commands::Abort { subcommand } => {
+            if let Err(e) = process_control_subcommands(
+                &mut establish_pg_connection(),
+                subcommand,
+                ControlAction::Abort,
+            ) {
+                println!("Failed to abort, {}", e);
+                std::process::exit(1);
+            };
         }
         Commands::List { subcommand } => {
             if let Err(e) = process_list_subcommands(&mut establish_pg_connection(), subcommand) {
@@ -229,6 +413,77 @@ pub fn cli() {
     std::process::exit(0);
 }
 
+fn process_stop_command(engine: Option<&str>, drain: bool) -> Result<(), AnyError> {
+    let conn = &mut establish_pg_connection();
+    let engine_uid = engine
+        .map(|target| resolve_engine_uid(conn, target))
+        .transpose()?;
+    let stopping_uids = handle_stop(conn, engine_uid, drain)?;
+    if stopping_uids.is_empty() {
+        println!("No running engine to stop");
+        return Ok(());
+    }
+    println!(
+        "{} engines {:?}",
+        if drain { "Draining" } else { "Stopping" },
+        stopping_uids
+    );
+    wait_for_engines_to_stop(conn, &stopping_uids)?;
+    println!("Stopped");
+    Ok(())
+}
+
+fn process_restart_command(engine: &str, drain: bool, force: bool) -> Result<(), AnyError> {
+    let conn = &mut establish_pg_connection();
+    let engine_uid = resolve_engine_uid(conn, engine)?;
+    let engine = workflow::schema::engines::dsl::engines
+        .find(engine_uid)
+        .select(Engine::as_select())
+        .first::<Engine>(conn)?;
+    // The processes are started by this command, on this machine
+    let ip_address = get_system_ip_address()?;
+    if engine.ip_address != ip_address {
+        return Err(anyhow!(
+            "engine {} runs on {}, restart it from there",
+            engine_uid,
+            engine.ip_address
+        ));
+    }
+
+    if !handle_stop(conn, Some(engine_uid), drain)?.is_empty() {
+        println!(
+            "{} engine {}",
+            if drain { "Draining" } else { "Stopping" },
+            engine_uid
+        );
+        wait_for_engines_to_stop(conn, &[engine_uid])?;
+    }
+
+    // Starting new processes next to old ones that are still running would run tasks twice
+    let engine = workflow::schema::engines::dsl::engines
+        .find(engine_uid)
+        .select(Engine::as_select())
+        .first::<Engine>(conn)?;
+    if !engine_processes_stopped(&engine) {
+        if !force {
+            return Err(anyhow!(
+                "engine {} is {} and its processes didn't report stopping, make sure they exited and restart it with --force",
+                engine_uid,
+                engine.status.to_lowercase()
+            ));
+        }
+        println!(
+            "Restarting engine {} without its processes reporting they stopped",
+            engine_uid
+        );
+    }
+
+    reset_engine(conn, engine_uid)?;
+    start_engine_processes(conn, engine_uid)?;
+    println!("Engine {} restarted successfully", engine_uid);
+    Ok(())
+}
+
 fn get_system_ip_address() -> Result<String, AnyError> {
     // Get a vector with all network interfaces found
     let all_interfaces = interfaces();
@@ -246,7 +501,6 @@ fn get_system_ip_address() -> Result<String, AnyError> {
 }
 
 fn process_start_command() -> Result<(), AnyError> {
-    use dotenv::dotenv;
     if let Err(e) = run_migrations() {
         eprintln!("Failed to run DB migrations: {}", e);
         eprintln!("exiting...");
@@ -262,6 +516,12 @@ fn process_start_command() -> Result<(), AnyError> {
     )?;
     println!("created new engine entry with uid: {}", engine_uid);
 
+    start_engine_processes(conn, engine_uid)?;
+    println!("Engine started successfully");
+    Ok(())
+}
+
+fn start_engine_processes(conn: &mut PgConnection, engine_uid: i32) -> Result<(), AnyError> {
     if let Err(e) = start_process("start-event-process", ProcessType::Event, engine_uid) {
         eprintln!("Failed to start Event process: {}", e);
         eprintln!("exiting...");
@@ -275,8 +535,6 @@ fn process_start_command() -> Result<(), AnyError> {
     }
 
     update_engine_status(conn, engine_uid, EngineStatus::Running)?;
-
-    println!("Engine started successfully");
     Ok(())
 }
 
@@ -290,20 +548,43 @@ fn process_show_subcommands(
             let item: Task = workflow::schema::tasks::dsl::tasks
                 .select(Task::as_select())
                 .filter(workflow::schema::tasks::dsl::uid.eq(uid))
+                .filter(workflow::schema::tasks::dsl::deleted_at.is_null())
                 .first::<Task>(conn)?;
-            list_items(vec![item])?
+            list_items(vec![item])?;
+
+            println!("Runs of task: {}", uid);
+            let task_runs = workflow::schema::task_runs::dsl::task_runs
+                .select(TaskRun::as_select())
+                .filter(workflow::schema::task_runs::dsl::task_uid.eq(uid))
+                .order(workflow::schema::task_runs::dsl::uid)
+                .load::<TaskRun>(conn)?;
+            list_items(task_runs)?
         }
         ShowSubcommands::Event { uid } => {
             println!("Showing event: {}", uid);
             let item: Event = workflow::schema::events::dsl::events
                 .select(Event::as_select())
                 .filter(workflow::schema::events::dsl::uid.eq(uid))
+                .filter(workflow::schema::events::dsl::deleted_at.is_null())
                 .first::<Event>(conn)?;
-            list_items(vec![item])?
+            list_items(vec![item])?;
+
+            println!("Fires of event: {}", uid);
+            let runs = workflow::schema::event_runs::dsl::event_runs
+                .select(EventRun::as_select())
+                .filter(workflow::schema::event_runs::dsl::event_uid.eq(uid))
+                .order(workflow::schema::event_runs::dsl::uid)
+                .load::<EventRun>(conn)?;
+            list_items(runs)?
         }
         ShowSubcommands::Workflow { uid } => {
             println!("Showing workflow: {}", uid);
-            todo!()
+            let item = workflow::schema::workflows::dsl::workflows
+                .select(Workflow::as_select())
+                .filter(workflow::schema::workflows::dsl::uid.eq(uid))
+                .filter(workflow::schema::workflows::dsl::deleted_at.is_null())
+                .first::<Workflow>(conn)?;
+            print_workflow_tree(conn, &item)?
         }
         ShowSubcommands::Engine { uid } => {
             println!("Showing engine: {}", uid);
@@ -313,10 +594,52 @@ fn process_show_subcommands(
                 .first::<Engine>(conn)?;
             list_items(vec![item])?
         }
+        ShowSubcommands::Run { uid } => {
+            println!("Showing run: {}", uid);
+            let item = workflow::schema::event_runs::dsl::event_runs
+                .inner_join(workflow::schema::events::table)
+                .select(EventRun::as_select())
+                .filter(workflow::schema::event_runs::dsl::uid.eq(uid))
+                .filter(workflow::schema::events::dsl::deleted_at.is_null())
+                .first::<EventRun>(conn)?;
+            list_items(vec![item])?;
+
+            println!("Task runs of run: {}", uid);
+            let task_runs = workflow::schema::task_runs::dsl::task_runs
+                .select(TaskRun::as_select())
+                .filter(workflow::schema::task_runs::dsl::run_uid.eq(uid))
+                .order(workflow::schema::task_runs::dsl::uid)
+                .load::<TaskRun>(conn)?;
+            list_items(task_runs)?
+        }
     }
     Ok(())
 }
 
+fn process_control_subcommands(
+    conn: &mut PgConnection,
+    subcommand: &ControlSubcommands,
+    action: ControlAction,
+) -> Result<(), AnyError> {
+    match subcommand {
+        ControlSubcommands::Task { target } => {
+            let task_uid = resolve_task_uid(conn, target)?;
+            println!("{} task: {}", action, task_uid);
+            control_task(conn, task_uid, action)
+        }
+        ControlSubcommands::Event { target } => {
+            let event_uid = resolve_event_uid(conn, target)?;
+            println!("{} event: {}", action, event_uid);
+            control_event(conn, event_uid, action)
+        }
+        ControlSubcommands::Workflow { target } => {
+            let workflow_uid = resolve_workflow_uid(conn, target)?;
+            println!("{} workflow: {}", action, workflow_uid);
+            control_workflow(conn, workflow_uid, action)
+        }
+    }
+}
+
 fn process_list_subcommands(
     conn: &mut PgConnection,
     subcommand: &ListSubcommands,
@@ -326,6 +649,7 @@ fn process_list_subcommands(
             println!("Listing tasks");
             let items = workflow::schema::tasks::dsl::tasks
                 .select(Task::as_select())
+                .filter(workflow::schema::tasks::dsl::deleted_at.is_null())
                 .load::<Task>(conn)?;
             list_items(items)
         }
@@ -333,25 +657,53 @@ fn process_list_subcommands(
             println!("Listing events");
             let items = workflow::schema::events::dsl::events
                 .select(Event::as_select())
+                .filter(workflow::schema::events::dsl::deleted_at.is_null())
                 .load::<Event>(conn)?;
             list_items(items)
         }
         ListSubcommands::Workflows {} => {
-            println!("Listing workflows, not implemented yet");
-            todo!()
+            println!("Listing workflows");
+            // Older versions are listed by `history`
+            let items = workflow::schema::workflows::dsl::workflows
+                .select(Workflow::as_select())
+                .filter(workflow::schema::workflows::dsl::active.eq(true))
+                .filter(workflow::schema::workflows::dsl::deleted_at.is_null())
+                .order(workflow::schema::workflows::dsl::uid)
+                .load::<Workflow>(conn)?;
+            for item in items {
+                print_workflow_tree(conn, &item)?;
+            }
+            Ok(())
         }
         ListSubcommands::Engines {} => {
             println!("Listing engines");
             let items = workflow::schema::engines::dsl::engines
-                .select(Engine::as_select())
-                .load::<Engine>(conn)?;
+                .select((Engine::as_select(), seconds_since_heartbeat()))
+                .order(workflow::schema::engines::dsl::uid)
+                .load::<(Engine, Option<f64>)>(conn)?
+                .into_iter()
+                .map(|(engine, seconds_since_heartbeat)| EngineWithLiveness {
+                    liveness: engine_liveness(&engine, seconds_since_heartbeat),
+                    engine,
+                })
+                .collect();
+            list_items::<EngineWithLiveness>(items)
+        }
+        ListSubcommands::Runs {} => {
+            println!("Listing runs");
+            let items = workflow::schema::event_runs::dsl::event_runs
+                .inner_join(workflow::schema::events::table)
+                .select(EventRun::as_select())
+                .filter(workflow::schema::events::dsl::deleted_at.is_null())
+                .order(workflow::schema::event_runs::dsl::uid)
+                .load::<EventRun>(conn)?;
             list_items(items)
         }
         ListSubcommands::All {} => {
             println!("Listing all");
             process_list_subcommands(conn, &ListSubcommands::Tasks {})?;
             process_list_subcommands(conn, &ListSubcommands::Events {})?;
-            // process_list_subcommands(conn, &ListSubcommands::Workflows {})?;
+            process_list_subcommands(conn, &ListSubcommands::Workflows {})?;
             process_list_subcommands(conn, &ListSubcommands::Engines {})
         }
     }
@@ -367,7 +719,89 @@ fn process_list_subcommands(
 //     Ok(())
 // }
 
+// Prints a workflow with its events and tasks as a tree
+fn print_workflow_tree(conn: &mut PgConnection, item: &Workflow) -> Result<(), AnyError> {
+    println!(
+        "Workflow {}: {} v{}{} - {}",
+        item.uid,
+        item.name.as_deref().unwrap_or("unnamed"),
+        item.version,
+        if item.active { "" } else { " (inactive)" },
+        item.description.as_deref().unwrap_or("")
+    );
+
+    let workflow_events = workflow::schema::events::dsl::events
+        .select(Event::as_select())
+        .filter(workflow::schema::events::dsl::workflow_uid.eq(item.uid))
+        .order(workflow::schema::events::dsl::uid)
+        .load::<Event>(conn)?;
+
+    for (event_index, event) in workflow_events.iter().enumerate() {
+        let is_last_event = event_index == workflow_events.len() - 1;
+        let (event_branch, event_indent) = if is_last_event {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        println!(
+            "{}Event {}: {} [{}] {}",
+            event_branch,
+            event.uid,
+            event.name.as_deref().unwrap_or("unnamed"),
+            event.status,
+            describe_event_trigger(event)
+        );
+
+        let event_tasks = workflow::schema::tasks::dsl::tasks
+            .select(Task::as_select())
+            .filter(workflow::schema::tasks::dsl::event_uid.eq(event.uid))
+            .order(workflow::schema::tasks::dsl::uid)
+            .load::<Task>(conn)?;
+
+        for (task_index, task) in event_tasks.iter().enumerate() {
+            let task_branch = if task_index == event_tasks.len() - 1 {
+                "└── "
+            } else {
+                "├── "
+            };
+            println!(
+                "{}{}Task {}: {} [{}] {}",
+                event_indent,
+                task_branch,
+                task.uid,
+                task.name.as_deref().unwrap_or("unnamed"),
+                task.status,
+                task.script()
+                    .map(|script| script.to_string())
+                    .unwrap_or_default()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn describe_event_trigger(event: &Event) -> String {
+    if let Some(schedule) = &event.schedule {
+        return format!(
+            "schedule: {} ({})",
+            schedule,
+            event.timezone.as_deref().unwrap_or("UTC")
+        );
+    }
+    if let Some(every_seconds) = event.every_seconds {
+        return format!("every: {}s", every_seconds);
+    }
+    event
+        .trigger_script()
+        .map(|script| script.to_string())
+        .unwrap_or_default()
+}
+
 fn list_items<T: serde::ser::Serialize>(items: Vec<T>) -> Result<(), AnyError> {
+    if items.is_empty() {
+        println!("Nothing to list");
+        return Ok(());
+    }
     let mut pretty_table = PrettyTable::new();
 
     let binding = serde_json::to_value(&items[0]).expect("Failed to serialize vector to JSON");
@@ -0,0 +1,520 @@
+use crate::engine::run::skip_dependents;
+use crate::export::rebuild_workflow;
+use crate::models::{EventStatus, TaskStatus, Workflow};
+use crate::parser::add_workflow;
+use crate::utils::{
+    create_redis_connection, push_task_to_queue, remove_task_from_delayed_queue,
+    remove_task_from_queue, PAUSED_QUEUE_NAME, QUEUE_NAME,
+};
+use anyhow::{anyhow, Error as AnyError};
+use diesel::prelude::*;
+use std::fmt::{self, Display, Formatter};
+
+// Task runs in these statuses can still be aborted
+const UNFINISHED_TASK_RUN_STATUSES: [TaskStatus; 6] = [
+    TaskStatus::Waiting,
+    TaskStatus::Pending,
+    TaskStatus::Paused,
+    TaskStatus::Running,
+    TaskStatus::Retrying,
+    TaskStatus::Interrupted,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ControlAction {
+    Pause,
+    Continue,
+    Abort,
+}
+
+impl Display for ControlAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlAction::Pause => write!(f, "Pause"),
+            ControlAction::Continue => write!(f, "Continue"),
+            ControlAction::Abort => write!(f, "Abort"),
+        }
+    }
+}
+
+// A target can either be a uid or a name, names have to be unique to be used.
+// Names only match the active version of a workflow, and its events and tasks
+pub fn resolve_task_uid(conn: &mut PgConnection, target: &str) -> Result<i32, AnyError> {
+    use crate::schema::tasks::dsl::*;
+
+    let uids: Vec<i32> = match target.parse::<i32>() {
+        Ok(task_uid) => tasks.select(uid).filter(uid.eq(task_uid)).load(conn)?,
+        Err(_) => tasks
+            .inner_join(crate::schema::events::table.inner_join(crate::schema::workflows::table))
+            .select(uid)
+            .filter(name.eq(target))
+            .filter(crate::schema::workflows::active.eq(true))
+            .load(conn)?,
+    };
+    match uids.as_slice() {
+        [task_uid] => Ok(*task_uid),
+        [] => Err(anyhow!("No task found matching '{}'", target)),
+        _ => Err(anyhow!(
+            "Multiple tasks are named '{}', use one of the uids instead: {:?}",
+            target,
+            uids
+        )),
+    }
+}
+
+pub fn resolve_event_uid(conn: &mut PgConnection, target: &str) -> Result<i32, AnyError> {
+    use crate::schema::events::dsl::*;
+
+    let uids: Vec<i32> = match target.parse::<i32>() {
+        Ok(event_uid) => events.select(uid).filter(uid.eq(event_uid)).load(conn)?,
+        Err(_) => events
+            .inner_join(crate::schema::workflows::table)
+            .select(uid)
+            .filter(name.eq(target))
+            .filter(crate::schema::workflows::active.eq(true))
+            .load(conn)?,
+    };
+    match uids.as_slice() {
+        [event_uid] => Ok(*event_uid),
+        [] => Err(anyhow!("No event found matching '{}'", target)),
+        _ => Err(anyhow!(
+            "Multiple events are named '{}', use one of the uids instead: {:?}",
+            target,
+            uids
+        )),
+    }
+}
+
+pub fn resolve_workflow_uid(conn: &mut PgConnection, target: &str) -> Result<i32, AnyError> {
+    use crate::schema::workflows::dsl::*;
+
+    let uids: Vec<i32> = match target.parse::<i32>() {
+        Ok(workflow_uid) => workflows
+            .select(uid)
+            .filter(uid.eq(workflow_uid))
+            .load(conn)?,
+        Err(_) => workflows
+            .select(uid)
+            .filter(name.eq(target))
+            .filter(active.eq(true))
+            .load(conn)?,
+    };
+    match uids.as_slice() {
+        [workflow_uid] => Ok(*workflow_uid),
+        [] => Err(anyhow!("No workflow found matching '{}'", target)),
+        _ => Err(anyhow!(
+            "Multiple workflows are named '{}', use one of the uids instead: {:?}",
+            target,
+            uids
+        )),
+    }
+}
+
+/// Every version of the workflow with the name, the oldest first
+pub fn workflow_history(
+    conn: &mut PgConnection,
+    workflow_name: &str,
+) -> Result<Vec<Workflow>, AnyError> {
+    use crate::schema::workflows::dsl::*;
+
+    let versions = workflows
+        .select(Workflow::as_select())
+        .filter(name.eq(workflow_name))
+        .order(version)
+        .load(conn)?;
+    if versions.is_empty() {
+        return Err(anyhow!("No workflow named '{}'", workflow_name));
+    }
+    Ok(versions)
+}
+
+/// Adds the definition of an older version of the workflow back as its latest version.
+/// Runs started on the current version finish on it, like they do when a new version is added.
+pub fn rollback_workflow(
+    conn: &mut PgConnection,
+    workflow_name: &str,
+    workflow_version: i32,
+) -> Result<(i32, i32), AnyError> {
+    use crate::schema::workflows::dsl::*;
+
+    let workflow_uid: i32 = workflows
+        .select(uid)
+        .filter(name.eq(workflow_name))
+        .filter(version.eq(workflow_version))
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| {
+            anyhow!(
+                "Workflow '{}' has no version {}",
+                workflow_name,
+                workflow_version
+            )
+        })?;
+    let (workflow_file, workflow_path) = rebuild_workflow(conn, workflow_uid)?;
+    let workflow_path = match workflow_path {
+        Some(workflow_path) => workflow_path,
+        None => std::env::current_dir()?,
+    };
+    add_workflow(conn, &workflow_file, None, &workflow_path)
+}
+
+/// Pausing a task keeps its runs from being picked up from the queue, including the runs of
+/// later fires, continuing puts them back in the queue, and aborting a task aborts its
+/// unfinished runs, running ones get killed by the task process.
+pub fn control_task(
+    conn: &mut PgConnection,
+    task_uid: i32,
+    action: ControlAction,
+) -> Result<(), AnyError> {
+    use crate::schema::tasks::dsl::*;
+
+    let current_status: String = tasks.find(task_uid).select(status).first(conn)?;
+    let mut redis_con = create_redis_connection()?;
+
+    match action {
+        ControlAction::Pause => {
+            if current_status == TaskStatus::Paused.to_string() {
+                return Err(anyhow!("Task {} is already paused", task_uid));
+            }
+            diesel::update(tasks.find(task_uid))
+                .set((
+                    status.eq(TaskStatus::Paused.to_string()),
+                    updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+            for task_run_uid in task_run_uids(
+                conn,
+                task_uid,
+                &[TaskStatus::Pending, TaskStatus::Interrupted],
+            )? {
+                set_task_run_status(conn, task_run_uid, TaskStatus::Paused)?;
+                // If the run is already queued, park it so no engine can pick it up
+                if let Some(light_task) =
+                    remove_task_from_queue(&mut redis_con, QUEUE_NAME, task_run_uid)?
+                {
+                    push_task_to_queue(&mut redis_con, PAUSED_QUEUE_NAME, &light_task)?;
+                }
+            }
+            for task_run_uid in task_run_uids(conn, task_uid, &[TaskStatus::Retrying])? {
+                if !pause_retrying_task_run(conn, task_run_uid)? {
+                    continue;
+                }
+                // Parked whether it was waiting for its next attempt or already queued for it
+                let light_task = match remove_task_from_delayed_queue(&mut redis_con, task_run_uid)?
+                {
+                    Some(light_task) => Some(light_task),
+                    None => remove_task_from_queue(&mut redis_con, QUEUE_NAME, task_run_uid)?,
+                };
+                if let Some(light_task) = light_task {
+                    push_task_to_queue(&mut redis_con, PAUSED_QUEUE_NAME, &light_task)?;
+                }
+            }
+        }
+        ControlAction::Continue => {
+            if current_status != TaskStatus::Paused.to_string() {
+                return Err(anyhow!(
+                    "Task {} is not paused, its status is {}",
+                    task_uid,
+                    current_status
+                ));
+            }
+            diesel::update(tasks.find(task_uid))
+                .set((
+                    status.eq(EventStatus::Created.to_string()),
+                    updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+            for task_run_uid in task_run_uids(conn, task_uid, &[TaskStatus::Paused])? {
+                set_task_run_status(conn, task_run_uid, TaskStatus::Pending)?;
+                // A run that wasn't parked yet is still in the queue, it will run as it is pending again
+                if let Some(light_task) =
+                    remove_task_from_queue(&mut redis_con, PAUSED_QUEUE_NAME, task_run_uid)?
+                {
+                    push_task_to_queue(&mut redis_con, QUEUE_NAME, &light_task)?;
+                }
+            }
+        }
+        ControlAction::Abort => {
+            let unfinished_task_run_uids =
+                task_run_uids(conn, task_uid, &UNFINISHED_TASK_RUN_STATUSES)?;
+            if unfinished_task_run_uids.is_empty() {
+                return Err(anyhow!("Task {} has no unfinished runs", task_uid));
+            }
+            for task_run_uid in unfinished_task_run_uids {
+                // A running task is killed by the task process once it sees the new status
+                if !abort_task_run(conn, task_run_uid)? {
+                    continue;
+                }
+                remove_task_from_queue(&mut redis_con, QUEUE_NAME, task_run_uid)?;
+                remove_task_from_queue(&mut redis_con, PAUSED_QUEUE_NAME, task_run_uid)?;
+                remove_task_from_delayed_queue(&mut redis_con, task_run_uid)?;
+                // Tasks of the same run waiting on this one will never be able to start
+                let parent_run_uid: Option<i32> = crate::schema::task_runs::table
+                    .find(task_run_uid)
+                    .select(crate::schema::task_runs::run_uid)
+                    .first(conn)?;
+                if let Some(parent_run_uid) = parent_run_uid {
+                    skip_dependents(conn, parent_run_uid, task_uid)?;
+                }
+            }
+        }
+    }
+    println!("{} task {}: done", action, task_uid);
+    Ok(())
+}
+
+/// Applies the action to the event itself, so it is no longer polled, and to all of its tasks
+pub fn control_event(
+    conn: &mut PgConnection,
+    event_uid: i32,
+    action: ControlAction,
+) -> Result<(), AnyError> {
+    use crate::schema::events::dsl::*;
+
+    let current_status: String = events.find(event_uid).select(status).first(conn)?;
+
+    match action {
+        ControlAction::Pause => {
+            // Finished, exhausted or aborted events are left as they are, continuing them would
+            // make them live again and a once event that already fired would fire again
+            let paused = diesel::update(events.find(event_uid))
+                .filter(status.eq_any(vec![
+                    EventStatus::Created.to_string(),
+                    EventStatus::Scheduled.to_string(),
+                    EventStatus::Triggered.to_string(),
+                    EventStatus::Retrying.to_string(),
+                    EventStatus::TimedOut.to_string(),
+                ]))
+                .set(status.eq(EventStatus::Paused.to_string()))
+                .execute(conn)?;
+            if paused == 0 {
+                println!(
+                    "Event {} is {}, only live events are paused",
+                    event_uid, current_status
+                );
+            }
+        }
+        ControlAction::Continue => {
+            // An exhausted event gets all of its attempts back
+            if current_status == EventStatus::Paused.to_string()
+                || current_status == EventStatus::Exhausted.to_string()
+            {
+                diesel::update(events.find(event_uid))
+                    .set((
+                        status.eq(EventStatus::Created.to_string()),
+                        attempts.eq(0),
+                        next_poll_at.eq(None::<chrono::NaiveDateTime>),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+        ControlAction::Abort => {
+            diesel::update(events.find(event_uid))
+                .set(status.eq(EventStatus::Aborted.to_string()))
+                .execute(conn)?;
+        }
+    }
+    println!("{} event {}: done", action, event_uid);
+
+    let task_uids: Vec<i32> = crate::schema::tasks::table
+        .select(crate::schema::tasks::uid)
+        .filter(crate::schema::tasks::event_uid.eq(event_uid))
+        .order(crate::schema::tasks::uid)
+        .load(conn)?;
+    for task_uid in task_uids {
+        // Tasks that are not in a state the action applies to are left as they are
+        if let Err(e) = control_task(conn, task_uid, action) {
+            println!("Skipping task {}, {}", task_uid, e);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the action to every event of the workflow, and through them to every task
+pub fn control_workflow(
+    conn: &mut PgConnection,
+    workflow_uid: i32,
+    action: ControlAction,
+) -> Result<(), AnyError> {
+    let event_uids: Vec<i32> = crate::schema::events::table
+        .select(crate::schema::events::uid)
+        .filter(crate::schema::events::workflow_uid.eq(workflow_uid))
+        .order(crate::schema::events::uid)
+        .load(conn)?;
+    for event_uid in event_uids {
+        control_event(conn, event_uid, action)?;
+    }
+    println!("{} workflow {}: done", action, workflow_uid);
+    Ok(())
+}
+
+/// Soft deletes every version of the workflow along with their events and tasks, so they are no
+/// longer polled, listed or shown. Unfinished runs are aborted, which drops them from the queues.
+/// Purging removes the rows for good.
+pub fn delete_workflow(
+    conn: &mut PgConnection,
+    workflow_uid: i32,
+    purge: bool,
+) -> Result<(), AnyError> {
+    let workflow_uids = workflow_version_uids(conn, workflow_uid)?;
+    conn.transaction(|conn| {
+        diesel::update(crate::schema::workflows::table)
+            .filter(crate::schema::workflows::uid.eq_any(&workflow_uids))
+            .filter(crate::schema::workflows::deleted_at.is_null())
+            .set(crate::schema::workflows::deleted_at.eq(diesel::dsl::now.nullable()))
+            .execute(conn)?;
+        diesel::update(crate::schema::events::table)
+            .filter(crate::schema::events::workflow_uid.eq_any(&workflow_uids))
+            .filter(crate::schema::events::deleted_at.is_null())
+            .set(crate::schema::events::deleted_at.eq(diesel::dsl::now.nullable()))
+            .execute(conn)?;
+        diesel::update(crate::schema::tasks::table)
+            .filter(crate::schema::tasks::event_uid.eq_any(workflow_event_uids(&workflow_uids)))
+            .filter(crate::schema::tasks::deleted_at.is_null())
+            .set(crate::schema::tasks::deleted_at.eq(diesel::dsl::now.nullable()))
+            .execute(conn)
+    })?;
+    println!("Deleted workflow {}", workflow_uid);
+
+    let task_uids: Vec<i32> = crate::schema::tasks::table
+        .select(crate::schema::tasks::uid)
+        .filter(crate::schema::tasks::event_uid.eq_any(workflow_event_uids(&workflow_uids)))
+        .order(crate::schema::tasks::uid)
+        .load(conn)?;
+    for task_uid in task_uids {
+        // Tasks without unfinished runs have nothing to abort
+        if let Err(e) = control_task(conn, task_uid, ControlAction::Abort) {
+            println!("Skipping task {}, {}", task_uid, e);
+        }
+    }
+
+    if purge {
+        // Events, tasks, their runs and dependencies go along with the workflow
+        diesel::delete(crate::schema::workflows::table)
+            .filter(crate::schema::workflows::uid.eq_any(&workflow_uids))
+            .execute(conn)?;
+        println!("Purged workflow {}", workflow_uid);
+    }
+    Ok(())
+}
+
+/// Brings back every version of a soft deleted workflow, runs aborted by the delete stay aborted
+pub fn restore_workflow(conn: &mut PgConnection, workflow_uid: i32) -> Result<(), AnyError> {
+    let workflow_uids = workflow_version_uids(conn, workflow_uid)?;
+    conn.transaction(|conn| {
+        let restored = diesel::update(crate::schema::workflows::table)
+            .filter(crate::schema::workflows::uid.eq_any(&workflow_uids))
+            .filter(crate::schema::workflows::deleted_at.is_not_null())
+            .set(crate::schema::workflows::deleted_at.eq(None::<chrono::NaiveDateTime>))
+            .execute(conn)?;
+        if restored == 0 {
+            return Err(anyhow!("Workflow {} is not deleted", workflow_uid));
+        }
+        diesel::update(crate::schema::events::table)
+            .filter(crate::schema::events::workflow_uid.eq_any(&workflow_uids))
+            .set(crate::schema::events::deleted_at.eq(None::<chrono::NaiveDateTime>))
+            .execute(conn)?;
+        diesel::update(crate::schema::tasks::table)
+            .filter(crate::schema::tasks::event_uid.eq_any(workflow_event_uids(&workflow_uids)))
+            .set(crate::schema::tasks::deleted_at.eq(None::<chrono::NaiveDateTime>))
+            .execute(conn)?;
+        Ok(())
+    })?;
+    println!("Restored workflow {}", workflow_uid);
+    Ok(())
+}
+
+// Uids of every version of the workflow, a workflow without a name only has the one
+fn workflow_version_uids(conn: &mut PgConnection, workflow_uid: i32) -> Result<Vec<i32>, AnyError> {
+    use crate::schema::workflows::dsl::*;
+
+    let workflow_name: Option<String> = workflows.find(workflow_uid).select(name).first(conn)?;
+    let uids = match workflow_name {
+        Some(workflow_name) => workflows
+            .select(uid)
+            .filter(name.eq(workflow_name))
+            .order(uid)
+            .load(conn)?,
+        None => vec![workflow_uid],
+    };
+    Ok(uids)
+}
+
+fn workflow_event_uids<'a>(
+    workflow_uids: &'a [i32],
+) -> crate::schema::events::BoxedQuery<'a, diesel::pg::Pg, diesel::sql_types::Integer> {
+    crate::schema::events::table
+        .select(crate::schema::events::uid)
+        .filter(crate::schema::events::workflow_uid.eq_any(workflow_uids))
+        .into_boxed()
+}
+
+fn task_run_uids(
+    conn: &mut PgConnection,
+    parent_task_uid: i32,
+    statuses: &[TaskStatus],
+) -> Result<Vec<i32>, AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    let statuses: Vec<String> = statuses.iter().map(|s| s.to_string()).collect();
+    let uids = task_runs
+        .select(uid)
+        .filter(task_uid.eq(parent_task_uid))
+        .filter(status.eq_any(statuses))
+        .order(uid)
+        .load(conn)?;
+    Ok(uids)
+}
+
+// The attempt the run was waiting for is counted here, as it runs as a pending run once it is
+// continued. Returns false if an engine started the attempt meanwhile, the run is left running.
+fn pause_retrying_task_run(conn: &mut PgConnection, task_run_uid: i32) -> Result<bool, AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    let paused = diesel::update(task_runs.find(task_run_uid))
+        .filter(status.eq(TaskStatus::Retrying.to_string()))
+        .set((
+            status.eq(TaskStatus::Paused.to_string()),
+            attempt.eq(attempt + 1),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(conn)?;
+    Ok(paused == 1)
+}
+
+// Returns false if the run finished since its status was read, its outcome is kept then
+fn abort_task_run(conn: &mut PgConnection, task_run_uid: i32) -> Result<bool, AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    let aborted = diesel::update(task_runs.find(task_run_uid))
+        .filter(
+            status.eq_any(
+                UNFINISHED_TASK_RUN_STATUSES
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<String>>(),
+            ),
+        )
+        .set((
+            status.eq(TaskStatus::Aborted.to_string()),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(conn)?;
+    Ok(aborted == 1)
+}
+
+fn set_task_run_status(
+    conn: &mut PgConnection,
+    task_run_uid: i32,
+    task_run_status: TaskStatus,
+) -> Result<(), AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    diesel::update(task_runs.find(task_run_uid))
+        .set((
+            status.eq(task_run_status.to_string()),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
@@ -1,19 +1,29 @@
-use crate::models::EngineStatus;
-use crate::utils::establish_pg_connection;
+use crate::models::{Engine, EngineStatus, ProcessStatus, TaskStatus};
 use crate::{models, schema};
-use anyhow::Error as AnyError;
+use anyhow::{anyhow, Error as AnyError};
 use ctrlc::set_handler;
+use diesel::expression::SqlLiteral;
+use diesel::sql_types::{Double, Nullable};
 use diesel::PgConnection;
+use std::collections::HashMap;
 use std::str;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use diesel::prelude::*;
 
 use self::event::poll_events;
+use self::process::{default_timeout_seconds, shutdown_grace_period};
+pub use self::reaper::ENGINE_LOST_AFTER_SECONDS;
 use self::task::queue_processor;
 
 mod event;
+mod lease;
+mod process;
+mod reaper;
+pub(crate) mod run;
 mod task;
 
 fn run_process<F>(process_name: &str, process_fn: F, engine_uid: i32) -> Result<(), AnyError>
@@ -28,6 +38,18 @@ where
     })
     .expect("Error setting Ctrl-C handler");
 
+    // An invalid engine wide timeout is reported before anything runs
+    if let Err(e) = default_timeout_seconds() {
+        eprintln!("Invalid DEFAULT_TIMEOUT: {}", e);
+        eprintln!("exiting...");
+        std::process::exit(1);
+    }
+    if let Err(e) = shutdown_grace_period() {
+        eprintln!("Invalid SHUTDOWN_GRACE_PERIOD: {}", e);
+        eprintln!("exiting...");
+        std::process::exit(1);
+    }
+
     if let Err(e) = process_fn(running, engine_uid) {
         eprintln!("Failed to start {} process: {}", process_name, e);
         eprintln!("exiting...");
@@ -46,10 +68,162 @@ pub fn run_event_process(engine_uid: i32) -> Result<(), AnyError> {
     run_process("Event", poll_events, engine_uid)
 }
 
-pub fn handle_stop() -> Result<(), AnyError> {
-    diesel::update(schema::engines::table)
-        .set(schema::engines::stop_signal.eq(true))
-        .execute(&mut establish_pg_connection())?;
+/// Resolves an engine from its uid or its name. A restarted engine keeps its uid, a name matches
+/// the engine that isn't stopped under it, or the latest one if they are all stopped.
+pub fn resolve_engine_uid(conn: &mut PgConnection, target: &str) -> Result<i32, AnyError> {
+    use crate::schema::engines::dsl::*;
+
+    if let Ok(engine_uid) = target.parse::<i32>() {
+        return engines
+            .select(uid)
+            .filter(uid.eq(engine_uid))
+            .first(conn)
+            .optional()?
+            .ok_or_else(|| anyhow!("No engine found matching '{}'", target));
+    }
+    let named_engines: Vec<(i32, String)> = engines
+        .select((uid, status))
+        .filter(name.eq(target))
+        .order(uid.desc())
+        .load(conn)?;
+    let not_stopped: Vec<i32> = named_engines
+        .iter()
+        .filter(|(_, engine_status)| *engine_status != EngineStatus::Stopped.to_string())
+        .map(|(engine_uid, _)| *engine_uid)
+        .collect();
+    match (not_stopped.as_slice(), named_engines.first()) {
+        ([engine_uid], _) => Ok(*engine_uid),
+        ([], Some((latest_uid, _))) => Ok(*latest_uid),
+        ([], None) => Err(anyhow!("No engine found matching '{}'", target)),
+        _ => Err(anyhow!(
+            "Multiple engines named '{}' aren't stopped, use one of the uids instead: {:?}",
+            target,
+            not_stopped
+        )),
+    }
+}
+
+/// Signals the engine, or every engine, to stop. A draining engine takes no new tasks and stops
+/// once its running tasks are done. Returns the uids of the engines signalled, the ones already
+/// stopped or lost are left alone.
+pub fn handle_stop(
+    conn: &mut PgConnection,
+    engine_uid: Option<i32>,
+    drain: bool,
+) -> Result<Vec<i32>, AnyError> {
+    use crate::schema::engines::dsl::*;
+
+    let mut query = diesel::update(engines)
+        .filter(status.ne_all(vec![
+            EngineStatus::Stopped.to_string(),
+            EngineStatus::Lost.to_string(),
+        ]))
+        .into_boxed();
+    if let Some(engine_uid) = engine_uid {
+        query = query.filter(uid.eq(engine_uid));
+    }
+    let signalled_uids = query
+        .set((stop_signal.eq(true), drain_signal.eq(drain)))
+        .returning(uid)
+        .get_results(conn)?;
+    Ok(signalled_uids)
+}
+
+/// Reports the progress of stopping engines until both processes of each of them are stopped,
+/// or it turns out to be lost
+pub fn wait_for_engines_to_stop(
+    conn: &mut PgConnection,
+    engine_uids: &[i32],
+) -> Result<(), AnyError> {
+    use crate::schema::engines::dsl::*;
+
+    let mut last_progress: HashMap<i32, String> = HashMap::new();
+    loop {
+        let stopping_engines: Vec<(Engine, Option<f64>, f64)> = engines
+            .select((
+                Engine::as_select(),
+                seconds_since_heartbeat(),
+                diesel::dsl::sql::<Double>("EXTRACT(EPOCH FROM NOW() - started_at)::float8"),
+            ))
+            .filter(uid.eq_any(engine_uids))
+            .order(uid)
+            .load(conn)?;
+
+        let mut waiting = false;
+        for (engine, seconds_since_heartbeat, seconds_since_start) in stopping_engines {
+            let stopped = engine.task_process_status == ProcessStatus::Stopped.to_string()
+                && engine.event_process_status == ProcessStatus::Stopped.to_string();
+            // An engine that went silent won't report stopping, there is nothing to wait for.
+            // Engines that never wrote a heartbeat are given the same time from their start,
+            // as the reaper does.
+            let silent = seconds_since_heartbeat.unwrap_or(seconds_since_start)
+                > ENGINE_LOST_AFTER_SECONDS as f64;
+            let gone = !stopped && (engine.status == EngineStatus::Lost.to_string() || silent);
+            let running_tasks: i64 = schema::task_runs::table
+                .filter(schema::task_runs::engine_uid.eq(engine.uid))
+                .filter(schema::task_runs::status.eq(TaskStatus::Running.to_string()))
+                .count()
+                .get_result(conn)?;
+            let progress = format!(
+                "Engine {} ({}): task process {}, event process {}, {} running tasks{}",
+                engine.uid,
+                engine.name,
+                process_status_or_not_started(&engine.task_process_status),
+                process_status_or_not_started(&engine.event_process_status),
+                running_tasks,
+                if gone {
+                    format!(", {}", engine_liveness(&engine, seconds_since_heartbeat))
+                } else {
+                    String::new()
+                }
+            );
+            if last_progress.get(&engine.uid) != Some(&progress) {
+                println!("{}", progress);
+                last_progress.insert(engine.uid, progress);
+            }
+            if !stopped && !gone {
+                waiting = true;
+            }
+        }
+        if !waiting {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(1000));
+    }
+}
+
+fn process_status_or_not_started(process_status: &str) -> &str {
+    if process_status.is_empty() {
+        "not started"
+    } else {
+        process_status
+    }
+}
+
+/// Whether both processes of an engine reported stopping, or were never started. The processes
+/// of a lost engine haven't reported anything, they may still be running.
+pub fn engine_processes_stopped(engine: &Engine) -> bool {
+    [&engine.task_process_status, &engine.event_process_status]
+        .iter()
+        .all(|process_status| {
+            process_status.is_empty() || **process_status == ProcessStatus::Stopped.to_string()
+        })
+}
+
+/// Gets a stopped engine ready to start its processes again under the same uid
+pub fn reset_engine(conn: &mut PgConnection, engine_uid: i32) -> Result<(), AnyError> {
+    use crate::schema::engines::dsl::*;
+
+    diesel::update(engines.find(engine_uid))
+        .set((
+            status.eq(EngineStatus::Starting.to_string()),
+            stop_signal.eq(false),
+            drain_signal.eq(false),
+            started_at.eq(diesel::dsl::now),
+            last_heartbeat_at.eq(None::<chrono::NaiveDateTime>),
+            event_heartbeat_at.eq(None::<chrono::NaiveDateTime>),
+        ))
+        .execute(conn)?;
     Ok(())
 }
 
@@ -68,6 +242,32 @@ pub fn update_engine_status(
     Ok(())
 }
 
+/// Seconds since the latest heartbeat of either process of an engine. The age is computed by the
+/// database, the clock the heartbeats were written with.
+pub fn seconds_since_heartbeat() -> SqlLiteral<Nullable<Double>> {
+    diesel::dsl::sql(
+        "EXTRACT(EPOCH FROM NOW() - GREATEST(last_heartbeat_at, event_heartbeat_at))::float8",
+    )
+}
+
+/// Describes whether an engine is still alive, from its status and the seconds since the last
+/// heartbeat of its processes
+pub fn engine_liveness(engine: &Engine, seconds_since_heartbeat: Option<f64>) -> String {
+    if engine.status == EngineStatus::Stopped.to_string() {
+        return "stopped".to_string();
+    }
+    if engine.status == EngineStatus::Lost.to_string() {
+        return "lost".to_string();
+    }
+    match seconds_since_heartbeat {
+        None => "no heartbeat yet".to_string(),
+        Some(seconds) if seconds <= ENGINE_LOST_AFTER_SECONDS as f64 => {
+            format!("alive ({:.0}s ago)", seconds)
+        }
+        Some(seconds) => format!("unresponsive ({:.0}s ago)", seconds),
+    }
+}
+
 pub fn create_new_engine_entry(
     conn: &mut PgConnection,
     name: &str,
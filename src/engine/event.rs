@@ -1,15 +1,26 @@
-use crate::models::{EventStatus, LightEvent, LightTask, ProcessStatus};
+use super::lease::{claim_events, hold_event_lease, live_events, release_engine_events};
+use super::process::{effective_timeout, spawn_script, wait_for_child, Interruption};
+use super::reaper::{record_heartbeat, EngineProcess};
+use super::run::{event_env_and_args, start_run};
+use crate::models::{Backoff, EventMode, EventStatus, LightEvent, NewEventRun, ProcessStatus};
+use crate::schedule::{backoff_delay_seconds, next_fire_time};
 use crate::schema;
-use crate::utils::{establish_pg_connection, push_tasks_to_queue};
+use crate::utils::establish_pg_connection;
 use anyhow::Error as AnyError;
+use diesel::dsl::IntervalDsl;
 use diesel::prelude::*;
-use std::path::Path;
-use std::process::Command as ShellCommand;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{str, thread};
 
+// A trigger prints `WORKFLOW_PAYLOAD={"key": "value"}` to hand a JSON payload to the tasks
+const PAYLOAD_LINE_MARKER: &str = "WORKFLOW_PAYLOAD=";
+// Script triggered events without a poll_interval are polled on every loop
+const DEFAULT_POLL_INTERVAL_SECONDS: i64 = 2;
+// How often the lease of an event is renewed while its trigger runs
+const LEASE_RENEWAL_INTERVAL: Duration = Duration::from_secs(5);
+
 pub fn poll_events(running: Arc<AtomicBool>, engine_uid: i32) -> Result<(), AnyError> {
     let mut event_uids: Vec<i32> = Vec::new();
     let pg_conn = &mut establish_pg_connection();
@@ -22,15 +33,57 @@ pub fn poll_events(running: Arc<AtomicBool>, engine_uid: i32) -> Result<(), AnyE
         .execute(pg_conn)?;
 
     while running.load(Ordering::SeqCst) {
-        let events: Vec<LightEvent> = schema::events::dsl::events
+        if !record_heartbeat(pg_conn, engine_uid, EngineProcess::Event)? {
+            println!("Engine {} was declared lost, stopping", engine_uid);
+            return Ok(());
+        }
+
+        // Only the events this engine holds the lease of are evaluated here
+        if let Err(e) = claim_events(pg_conn, engine_uid) {
+            eprintln!("Failed to claim events {}", e);
+        }
+        let events: Vec<LightEvent> = live_events()
             .select(LightEvent::as_select())
-            .filter(schema::events::status.ne(EventStatus::Succeeded.to_string()))
+            .filter(schema::events::owner_engine_uid.eq(engine_uid))
+            // Script triggered events are left alone until their next poll is due
+            .filter(
+                schema::events::next_poll_at
+                    .is_null()
+                    .or(schema::events::next_poll_at.le(diesel::dsl::now)),
+            )
+            // Recurring events with a cooldown are left alone until it has passed since their last fire
+            .filter(diesel::dsl::sql::<diesel::sql_types::Bool>(
+                "(cooldown_seconds IS NULL OR triggered_at IS NULL \
+                 OR triggered_at + cooldown_seconds * INTERVAL '1 second' <= NOW())",
+            ))
             .load(pg_conn)?;
 
         for event in events {
+            // A trigger running past the lease of the events loaded with it lets another engine
+            // take them over in the meantime
+            if !hold_event_lease(pg_conn, engine_uid, event.uid)? {
+                println!(
+                    "Event {} was taken over by another engine, skipping it",
+                    event.uid
+                );
+                continue;
+            }
             println!("Event: {}", event);
-            // async execute_event
-            let _ = execute_event(event);
+            if event.is_time_based() {
+                if let Err(e) = fire_time_based_event(event) {
+                    eprintln!("Failed to fire time based event {}", e);
+                }
+            } else if let Err(e) = execute_event(&event, engine_uid) {
+                // A trigger that can't be run counts as a failed attempt, so the event backs off
+                // and is given up on after max_attempts like any failing trigger
+                eprintln!("Failed to run the trigger of event {} {}", event.uid, e);
+                if let Err(e) = record_failed_attempt(pg_conn, &event, false) {
+                    eprintln!(
+                        "Failed to record the failed attempt of event {} {}",
+                        event.uid, e
+                    );
+                }
+            }
         }
 
         if event_uids.is_empty() {
@@ -66,6 +119,8 @@ pub fn poll_events(running: Arc<AtomicBool>, engine_uid: i32) -> Result<(), AnyE
         println!("\nCtrl+C signal detected. Exiting...");
     }
 
+    // The events of a stopped engine are taken over right away instead of once their leases expire
+    release_engine_events(pg_conn, engine_uid)?;
     diesel::update(engines)
         .filter(uid.eq(engine_uid))
         .set(event_process_status.eq(ProcessStatus::Stopped.to_string()))
@@ -73,45 +128,81 @@ pub fn poll_events(running: Arc<AtomicBool>, engine_uid: i32) -> Result<(), AnyE
     Ok(())
 }
 
-fn execute_event(event: LightEvent) -> Result<(), AnyError> {
+fn execute_event(event: &LightEvent, engine_uid: i32) -> Result<(), AnyError> {
     println!("Event Executor");
 
     let conn = &mut establish_pg_connection();
 
-    let path_basename = match Path::new(&event.trigger).file_name() {
-        Some(basename) => basename,
-        None => return Err(AnyError::msg("Failed to get path basename")),
+    let trigger_script = match event.trigger_script() {
+        Some(trigger_script) => trigger_script,
+        None => return Err(AnyError::msg("Event has no trigger")),
     };
-    let path_dirname = Path::new(&event.trigger).parent().unwrap();
-
-    let output = ShellCommand::new("bash")
-        .arg(path_basename)
-        .current_dir(path_dirname)
-        .output()
-        .expect("failed to execute process");
+    let timeout = effective_timeout(event.timeout_seconds)?;
+    // The trigger runs with the environment variables and arguments of its event
+    let (trigger_env, trigger_args) = event_env_and_args(conn, event.uid)?;
+    let trigger_env: Vec<(String, String)> = trigger_env.into_iter().collect();
+    let child = spawn_script(&trigger_script, &trigger_args, &trigger_env)?;
+    // The lease of the event is renewed while its trigger runs, so no other engine runs it meanwhile.
+    // The trigger is killed if the lease was lost anyway, the event belongs to another engine then.
+    let mut lease_renewed_at = Instant::now();
+    let mut lease_lost = false;
+    let (output, interruption) = wait_for_child(child, timeout, || {
+        if lease_renewed_at.elapsed() >= LEASE_RENEWAL_INTERVAL {
+            if !hold_event_lease(conn, engine_uid, event.uid)? {
+                lease_lost = true;
+                return Ok(true);
+            }
+            lease_renewed_at = Instant::now();
+        }
+        Ok(false)
+    })?;
+    if lease_lost {
+        println!(
+            "Lost the lease of event {} while its trigger ran, leaving it to its new owner",
+            event.uid
+        );
+        return Ok(());
+    }
 
     // if shell command return 0, then the event was triggered successfully
     use crate::schema::events::dsl::*;
-    if output.status.code().unwrap() == 0 {
-        diesel::update(events.find(event.uid))
-            .set(status.eq(EventStatus::Succeeded.to_string()))
-            .execute(conn)?;
-
-        {
-            use crate::schema::tasks::dsl::*;
-            let light_tasks: Vec<LightTask> = tasks
-                .select(LightTask::as_select())
-                .filter(event_uid.eq(event.uid))
-                .load(conn)?;
-            let _ = push_tasks_to_queue(light_tasks);
+    if interruption.is_none() && output.status.success() {
+        let poll_delay_seconds = event_poll_interval_seconds(event);
+        let ready_tasks = conn.transaction::<_, AnyError, _>(|conn| {
+            // Holding the lease locks the event, no other engine can claim it before this commits
+            if !hold_event_lease(conn, engine_uid, event.uid)? {
+                return Ok(None);
+            }
+            diesel::update(events.find(event.uid))
+                .set((
+                    status.eq(status_after_fire(event).to_string()),
+                    triggered_at.eq(diesel::dsl::now),
+                    attempts.eq(0),
+                    next_poll_at.eq((diesel::dsl::now + poll_delay_seconds.seconds()).nullable()),
+                ))
+                .execute(conn)?;
+            let trigger_stdout = str::from_utf8(&output.stdout)?;
+            let run_uid = insert_event_run(
+                conn,
+                event.uid,
+                Some(trigger_stdout),
+                Some(str::from_utf8(&output.stderr)?),
+                extract_payload(trigger_stdout),
+            )?;
+            Ok(Some(start_run(conn, event.uid, run_uid)?))
+        })?;
+        match ready_tasks {
+            Some(ready_tasks) => ready_tasks.push()?,
+            None => {
+                println!(
+                    "Lost the lease of event {} once its trigger succeeded, leaving it to its new owner",
+                    event.uid
+                );
+                return Ok(());
+            }
         }
     } else {
-        diesel::update(events.find(event.uid))
-            .set((
-                status.eq(EventStatus::Retrying.to_string()),
-                triggered_at.eq(diesel::dsl::now),
-            ))
-            .execute(conn)?;
+        record_failed_attempt(conn, event, interruption == Some(Interruption::TimedOut))?;
     };
 
     diesel::update(schema::events::dsl::events.find(event.uid))
@@ -123,7 +214,7 @@ fn execute_event(event: LightEvent) -> Result<(), AnyError> {
 
     println!(
         "event id: {} , trigger: {}\nFinished executing with a status: {}",
-        event.uid, event.trigger, output.status
+        event.uid, trigger_script, output.status
     );
     println!("##############################################");
     println!("stdout: {}", str::from_utf8(&output.stdout)?);
@@ -132,3 +223,203 @@ fn execute_event(event: LightEvent) -> Result<(), AnyError> {
     println!("##############################################");
     Ok(())
 }
+
+fn event_poll_interval_seconds(event: &LightEvent) -> i64 {
+    event
+        .poll_interval_seconds
+        .unwrap_or(DEFAULT_POLL_INTERVAL_SECONDS)
+}
+
+// A failed or timed out trigger is tried again after a delay growing with the event's backoff,
+// until it has failed max_attempts times in a row
+fn record_failed_attempt(
+    conn: &mut PgConnection,
+    event: &LightEvent,
+    timed_out: bool,
+) -> Result<(), AnyError> {
+    use crate::schema::events::dsl::*;
+
+    let failed_attempts = event.attempts + 1;
+    if event
+        .max_attempts
+        .is_some_and(|limit| failed_attempts >= limit)
+    {
+        println!(
+            "event id: {} failed {} attempts in a row, it won't be polled anymore",
+            event.uid, failed_attempts
+        );
+        diesel::update(events.find(event.uid))
+            .set((
+                status.eq(EventStatus::Exhausted.to_string()),
+                attempts.eq(failed_attempts),
+                next_poll_at.eq(None::<chrono::NaiveDateTime>),
+            ))
+            .execute(conn)?;
+        return Ok(());
+    }
+
+    let event_backoff = event.backoff.parse::<Backoff>().unwrap_or(Backoff::Fixed);
+    let delay_seconds = backoff_delay_seconds(
+        &event_backoff,
+        event_poll_interval_seconds(event),
+        failed_attempts,
+    );
+    let failed_status = if timed_out {
+        EventStatus::TimedOut
+    } else {
+        EventStatus::Retrying
+    };
+    diesel::update(events.find(event.uid))
+        .set((
+            status.eq(failed_status.to_string()),
+            attempts.eq(failed_attempts),
+            next_poll_at.eq((diesel::dsl::now + delay_seconds.seconds()).nullable()),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+// Time based events fire once their due time has passed, no trigger script is run
+fn fire_time_based_event(event: LightEvent) -> Result<(), AnyError> {
+    use crate::schema::events::dsl::*;
+    let conn = &mut establish_pg_connection();
+    let now = chrono::Utc::now().naive_utc();
+
+    let due_at = match event.next_fire_at {
+        Some(due_at) => due_at,
+        None => {
+            // The event hasn't been seen by any engine yet, it fires at its first due time
+            let first_fire_at = next_fire_time(
+                event.schedule.as_deref(),
+                event.timezone.as_deref(),
+                event.every_seconds,
+                now,
+            )?;
+            diesel::update(events.find(event.uid).filter(next_fire_at.is_null()))
+                .set((
+                    next_fire_at.eq(first_fire_at),
+                    status.eq(EventStatus::Scheduled.to_string()),
+                ))
+                .execute(conn)?;
+            return Ok(());
+        }
+    };
+    if now < due_at {
+        return Ok(());
+    }
+
+    // Missed fires, while the engine was down, are caught up with a single fire
+    let following_fire_at = match status_after_fire(&event) {
+        EventStatus::Succeeded => None,
+        _ => next_fire_time(
+            event.schedule.as_deref(),
+            event.timezone.as_deref(),
+            event.every_seconds,
+            now,
+        )?,
+    };
+    let following_status = match following_fire_at {
+        Some(_) => EventStatus::Scheduled,
+        None => EventStatus::Succeeded,
+    };
+
+    let ready_tasks = conn.transaction::<_, AnyError, _>(|conn| {
+        // Only moving next_fire_at away from the due time we read allows firing,
+        // so the same due time can't fire twice, even after a restart
+        let updated_rows = diesel::update(events.find(event.uid).filter(next_fire_at.eq(due_at)))
+            .set((
+                next_fire_at.eq(following_fire_at),
+                status.eq(following_status.to_string()),
+                triggered_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn)?;
+        if updated_rows == 1 {
+            println!("event id: {} is due since {}, firing it", event.uid, due_at);
+            let run_uid = insert_event_run(conn, event.uid, None, None, None)?;
+            return Ok(Some(start_run(conn, event.uid, run_uid)?));
+        }
+        Ok(None)
+    })?;
+    // Only pushed once the task runs are committed
+    match ready_tasks {
+        Some(ready_tasks) => ready_tasks.push(),
+        None => Ok(()),
+    }
+}
+
+// Once events are done after their first fire, recurring events go back to being polled
+fn status_after_fire(event: &LightEvent) -> EventStatus {
+    match event.mode.parse::<EventMode>() {
+        Ok(EventMode::Recurring) if event.is_time_based() => EventStatus::Scheduled,
+        Ok(EventMode::Recurring) => EventStatus::Triggered,
+        _ => EventStatus::Succeeded,
+    }
+}
+
+// The payload is the JSON document printed on a line starting with the payload marker,
+// or the whole stdout of the trigger when it didn't print one
+fn extract_payload(trigger_stdout: &str) -> Option<&str> {
+    let marked_payload = trigger_stdout
+        .lines()
+        .rev()
+        .find_map(|line| line.strip_prefix(PAYLOAD_LINE_MARKER));
+    match marked_payload {
+        Some(payload) => {
+            if let Err(e) = serde_json::from_str::<serde_json::Value>(payload) {
+                println!("Trigger printed a payload that isn't valid JSON, {}", e);
+            }
+            Some(payload.trim())
+        }
+        None if trigger_stdout.trim().is_empty() => None,
+        None => Some(trigger_stdout),
+    }
+}
+
+// Keeps the history of every fire of an event
+fn insert_event_run(
+    conn: &mut PgConnection,
+    fired_event_uid: i32,
+    fire_stdout: Option<&str>,
+    fire_stderr: Option<&str>,
+    fire_payload: Option<&str>,
+) -> Result<i32, AnyError> {
+    let new_event_run = NewEventRun {
+        event_uid: fired_event_uid,
+        status: EventStatus::Succeeded.to_string(),
+        stdout: fire_stdout,
+        stderr: fire_stderr,
+        payload: fire_payload,
+    };
+    let event_run_uid = diesel::insert_into(schema::event_runs::table)
+        .values(&new_event_run)
+        .returning(schema::event_runs::uid)
+        .get_result::<i32>(conn)?;
+    Ok(event_run_uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_the_last_marked_payload() {
+        let stdout = format!(
+            "checking\n{}{{\"temp\": 1}}\n{} {{\"temp\": 2}} \ndone\n",
+            PAYLOAD_LINE_MARKER, PAYLOAD_LINE_MARKER
+        );
+        assert_eq!(extract_payload(&stdout), Some("{\"temp\": 2}"));
+    }
+
+    #[test]
+    fn keeps_marked_payloads_that_are_not_json() {
+        let stdout = format!("{}not json\n", PAYLOAD_LINE_MARKER);
+        assert_eq!(extract_payload(&stdout), Some("not json"));
+    }
+
+    #[test]
+    fn uses_the_whole_stdout_without_a_marked_payload() {
+        assert_eq!(extract_payload("{\"temp\": 3}\n"), Some("{\"temp\": 3}\n"));
+        assert_eq!(extract_payload("it is warm\n"), Some("it is warm\n"));
+        assert_eq!(extract_payload(" \n"), None);
+    }
+}
@@ -0,0 +1,144 @@
+use super::reaper::ENGINE_LOST_AFTER_SECONDS;
+use crate::models::{EventStatus, ProcessStatus};
+use crate::schema;
+use anyhow::Error as AnyError;
+use diesel::dsl::IntervalDsl;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+
+// Each event is evaluated by the one engine holding its lease. The owner renews the leases of its
+// events on every loop, another engine takes an event over once its lease expires.
+pub const EVENT_LEASE_SECONDS: i64 = 30;
+
+// The events that are evaluated by some engine: not finished, not deleted and of an active workflow
+pub(crate) fn live_events() -> schema::events::BoxedQuery<'static, Pg> {
+    use crate::schema::events::dsl::*;
+
+    events
+        .filter(status.ne_all(vec![
+            EventStatus::Succeeded.to_string(),
+            EventStatus::Paused.to_string(),
+            EventStatus::Aborted.to_string(),
+            EventStatus::Exhausted.to_string(),
+        ]))
+        .filter(deleted_at.is_null())
+        // Events of older versions of a workflow don't fire anymore
+        .filter(
+            workflow_uid.eq_any(
+                schema::workflows::table
+                    .select(schema::workflows::uid.nullable())
+                    .filter(schema::workflows::active.eq(true)),
+            ),
+        )
+        .into_boxed()
+}
+
+// Renews the leases of the events owned by the engine and claims its share of the live events,
+// the events are split evenly between the live engines. An engine owning more than its share
+// releases the extra events, so an engine that joins gets events too.
+pub(crate) fn claim_events(conn: &mut PgConnection, engine_uid: i32) -> Result<(), AnyError> {
+    use crate::schema::events::dsl::*;
+
+    conn.transaction(|conn| {
+        diesel::update(events)
+            .filter(owner_engine_uid.eq(engine_uid))
+            .set(lease_expires_at.eq((diesel::dsl::now + EVENT_LEASE_SECONDS.seconds()).nullable()))
+            .execute(conn)?;
+
+        // Engines whose event process isn't running, or stopped writing its heartbeat, get no share
+        let live_engines: i64 = schema::engines::table
+            .filter(schema::engines::event_process_status.eq(ProcessStatus::Running.to_string()))
+            .filter(diesel::dsl::sql::<Bool>(&format!(
+                "COALESCE(event_heartbeat_at, started_at) >= NOW() - INTERVAL '{} seconds'",
+                ENGINE_LOST_AFTER_SECONDS
+            )))
+            .count()
+            .get_result(conn)?;
+        let live_event_count: i64 = live_events().count().get_result(conn)?;
+        let engine_count = live_engines.max(1);
+        let share = (live_event_count + engine_count - 1) / engine_count;
+        let owned: i64 = live_events()
+            .filter(owner_engine_uid.eq(engine_uid))
+            .count()
+            .get_result(conn)?;
+
+        if owned < share {
+            // Events locked by another engine claiming them at the same time are left to it
+            let claimable_uids: Vec<i32> = events
+                .select(uid)
+                .filter(uid.eq_any(live_events().select(uid)))
+                .filter(
+                    owner_engine_uid
+                        .is_null()
+                        .or(lease_expires_at.is_null())
+                        .or(lease_expires_at.lt(diesel::dsl::now)),
+                )
+                .order(uid)
+                .limit(share - owned)
+                .for_update()
+                .skip_locked()
+                .load(conn)?;
+            if !claimable_uids.is_empty() {
+                println!("Claiming events {:?}", claimable_uids);
+                diesel::update(events)
+                    .filter(uid.eq_any(claimable_uids))
+                    .set((
+                        owner_engine_uid.eq(engine_uid),
+                        lease_expires_at
+                            .eq((diesel::dsl::now + EVENT_LEASE_SECONDS.seconds()).nullable()),
+                    ))
+                    .execute(conn)?;
+            }
+        } else if owned > share {
+            let extra_uids: Vec<i32> = live_events()
+                .select(uid)
+                .filter(owner_engine_uid.eq(engine_uid))
+                .order(uid.desc())
+                .limit(owned - share)
+                .load(conn)?;
+            println!("Releasing events {:?} to other engines", extra_uids);
+            diesel::update(events)
+                .filter(uid.eq_any(extra_uids))
+                .set((
+                    owner_engine_uid.eq(None::<i32>),
+                    lease_expires_at.eq(None::<chrono::NaiveDateTime>),
+                ))
+                .execute(conn)?;
+        }
+        Ok(())
+    })
+}
+
+// Extends the lease of an event the engine is about to evaluate, or is evaluating,
+// returns false if the engine doesn't hold the lease anymore
+pub(crate) fn hold_event_lease(
+    conn: &mut PgConnection,
+    engine_uid: i32,
+    event_uid: i32,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::events::dsl::*;
+
+    let renewed = diesel::update(events.find(event_uid))
+        .filter(owner_engine_uid.eq(engine_uid))
+        .filter(lease_expires_at.gt(diesel::dsl::now))
+        .set(lease_expires_at.eq((diesel::dsl::now + EVENT_LEASE_SECONDS.seconds()).nullable()))
+        .execute(conn)?;
+    Ok(renewed == 1)
+}
+
+// Gives up the events owned by an engine, they are claimed by the next engine looking for events
+pub(crate) fn release_engine_events(
+    conn: &mut PgConnection,
+    engine_uid: i32,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::events::dsl::*;
+
+    diesel::update(events)
+        .filter(owner_engine_uid.eq(engine_uid))
+        .set((
+            owner_engine_uid.eq(None::<i32>),
+            lease_expires_at.eq(None::<chrono::NaiveDateTime>),
+        ))
+        .execute(conn)
+}
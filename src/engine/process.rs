@@ -0,0 +1,306 @@
+use crate::models::Script;
+use crate::schedule::parse_every;
+use anyhow::Error as AnyError;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command as ShellCommand, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+
+// How often a running child is checked for having exited, timed out or been aborted
+const CHILD_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// Time given to a timed out process group to exit after SIGTERM, before it gets SIGKILL
+const TIMEOUT_KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+// Only the start of a script is read to find its shebang line
+const SHEBANG_READ_LIMIT: u64 = 256;
+
+// Why a child didn't run to its end
+#[derive(PartialEq)]
+pub enum Interruption {
+    Aborted,
+    TimedOut,
+    // Killed because its engine shut down before it finished
+    Interrupted,
+}
+
+/// Engine wide timeout applied to tasks and triggers that don't set their own,
+/// set with the DEFAULT_TIMEOUT environment variable, such as `DEFAULT_TIMEOUT=1h`
+pub fn default_timeout_seconds() -> Result<Option<i64>, AnyError> {
+    match env::var("DEFAULT_TIMEOUT") {
+        Ok(default_timeout) => Ok(Some(parse_every(&default_timeout)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Time the running tasks of a shutting down engine get to finish before they are killed,
+/// set with the SHUTDOWN_GRACE_PERIOD environment variable, 30 seconds by default
+pub fn shutdown_grace_period() -> Result<Duration, AnyError> {
+    match env::var("SHUTDOWN_GRACE_PERIOD") {
+        Ok(grace_period) => Ok(Duration::from_secs(parse_every(&grace_period)? as u64)),
+        Err(_) => Ok(DEFAULT_SHUTDOWN_GRACE_PERIOD),
+    }
+}
+
+// The own timeout of a task or trigger wins over the engine wide one
+pub fn effective_timeout(timeout_seconds: Option<i64>) -> Result<Option<Duration>, AnyError> {
+    let timeout_seconds = match timeout_seconds {
+        Some(timeout_seconds) => Some(timeout_seconds),
+        None => default_timeout_seconds()?,
+    };
+    Ok(timeout_seconds.map(|seconds| Duration::from_secs(seconds as u64)))
+}
+
+// A running script, the file of an inline script is removed once it is dropped
+pub struct ScriptChild {
+    child: Child,
+    _inline_script: Option<NamedTempFile>,
+}
+
+// Runs the script, the child leads its own process group
+// so killing the group also kills anything the script spawned
+pub fn spawn_script<K: AsRef<OsStr>>(
+    script: &Script,
+    args: &[String],
+    envs: &[(K, String)],
+) -> Result<ScriptChild, AnyError> {
+    let mut inline_script = None;
+    let mut command = if let Some(command_line) = &script.command {
+        // An executable with its arguments, run as it is. Arguments are split like a shell would,
+        // quotes keep an argument with spaces together, but nothing else is interpreted
+        let words = shell_words::split(command_line)?;
+        let mut words = words.iter();
+        let program = words
+            .next()
+            .ok_or_else(|| AnyError::msg("Command is empty"))?;
+        let mut command = ShellCommand::new(program);
+        command.args(words);
+        command.current_dir(working_directory(script)?);
+        command
+    } else if let Some(run) = &script.run {
+        // Only readable by the engine user and under a name nobody can guess, the file is
+        // removed along with the child, or right away if the script fails to start
+        let mut script_file = tempfile::Builder::new()
+            .prefix("workflow_inline_")
+            .tempfile()?;
+        script_file.write_all(run.as_bytes())?;
+        let interpreter = script.interpreter.as_deref().unwrap_or("bash");
+        let mut command = interpreter_command(interpreter)?;
+        command.arg(script_file.path());
+        command.current_dir(working_directory(script)?);
+        inline_script = Some(script_file);
+        command
+    } else if let Some(script_path) = &script.path {
+        let path_basename = match Path::new(script_path).file_name() {
+            Some(basename) => basename,
+            None => return Err(AnyError::msg("Failed to get path basename")),
+        };
+        let path_dirname = Path::new(script_path).parent().unwrap();
+        let interpreter = match &script.interpreter {
+            Some(interpreter) => Some(interpreter.clone()),
+            None => default_interpreter(Path::new(script_path))?,
+        };
+        let mut command = match interpreter {
+            Some(interpreter) => {
+                let mut command = interpreter_command(&interpreter)?;
+                command.arg(path_basename);
+                command
+            }
+            None => ShellCommand::new(Path::new(".").join(path_basename)),
+        };
+        command.current_dir(path_dirname);
+        command
+    } else {
+        return Err(AnyError::msg("Nothing to run"));
+    };
+
+    let child = command
+        .args(args)
+        .envs(envs.iter().map(|(key, value)| (key, value)))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    Ok(ScriptChild {
+        child,
+        _inline_script: inline_script,
+    })
+}
+
+// The interpreter can come with its own arguments, such as `python3 -u`, split like a shell would
+fn interpreter_command(interpreter: &str) -> Result<ShellCommand, AnyError> {
+    let words = shell_words::split(interpreter)?;
+    let (program, args) = words
+        .split_first()
+        .ok_or_else(|| AnyError::msg("Interpreter is empty"))?;
+    let mut command = ShellCommand::new(program);
+    command.args(args);
+    Ok(command)
+}
+
+fn working_directory(script: &Script) -> Result<&str, AnyError> {
+    script
+        .working_directory
+        .as_deref()
+        .ok_or_else(|| AnyError::msg("Script has no working directory"))
+}
+
+// Executable files are run as they are, the others by the interpreter of their shebang line,
+// or by bash when they have none. None means the file is run directly
+fn default_interpreter(script_path: &Path) -> Result<Option<String>, AnyError> {
+    let is_executable = fs::metadata(script_path)?.permissions().mode() & 0o111 != 0;
+    if is_executable {
+        return Ok(None);
+    }
+    let mut first_bytes = Vec::new();
+    File::open(script_path)?
+        .take(SHEBANG_READ_LIMIT)
+        .read_to_end(&mut first_bytes)?;
+    let first_line = String::from_utf8_lossy(&first_bytes);
+    match first_line.lines().next().and_then(|l| l.strip_prefix("#!")) {
+        Some(shebang) if !shebang.trim().is_empty() => Ok(Some(shebang.trim().to_string())),
+        _ => Ok(Some("bash".to_string())),
+    }
+}
+
+/// Waits for the child to exit. Its process group is killed when `is_aborted` returns true,
+/// and terminated when it runs longer than the timeout, then killed if it is still there after a grace period.
+pub fn wait_for_child<F>(
+    mut script_child: ScriptChild,
+    timeout: Option<Duration>,
+    mut is_aborted: F,
+) -> Result<(Output, Option<Interruption>), AnyError>
+where
+    F: FnMut() -> Result<bool, AnyError>,
+{
+    let child = &mut script_child.child;
+    // Pipes are drained on their own threads, otherwise a chatty child blocks on a full pipe
+    let stdout_reader = spawn_pipe_reader(child.stdout.take());
+    let stderr_reader = spawn_pipe_reader(child.stderr.take());
+
+    let started = Instant::now();
+    let mut interruption = None;
+    let mut terminated_at: Option<Instant> = None;
+    let exit_status = loop {
+        if let Some(exit_status) = child.try_wait()? {
+            break exit_status;
+        }
+        match interruption {
+            None => {
+                if is_aborted()? {
+                    println!(
+                        "Process {} was aborted, killing its process group",
+                        child.id()
+                    );
+                    signal_process_group(child.id(), libc::SIGKILL);
+                    interruption = Some(Interruption::Aborted);
+                } else if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                    println!(
+                        "Process {} timed out, terminating its process group",
+                        child.id()
+                    );
+                    signal_process_group(child.id(), libc::SIGTERM);
+                    terminated_at = Some(Instant::now());
+                    interruption = Some(Interruption::TimedOut);
+                }
+            }
+            Some(Interruption::TimedOut) => {
+                if terminated_at.is_some_and(|at| at.elapsed() >= TIMEOUT_KILL_GRACE_PERIOD) {
+                    println!(
+                        "Process {} is still running after SIGTERM, killing its process group",
+                        child.id()
+                    );
+                    signal_process_group(child.id(), libc::SIGKILL);
+                    terminated_at = None;
+                }
+            }
+            Some(Interruption::Aborted | Interruption::Interrupted) => {}
+        }
+        thread::sleep(CHILD_CHECK_INTERVAL);
+    };
+
+    // Whatever the script left behind would keep the pipes open
+    if interruption.is_some() {
+        signal_process_group(child.id(), libc::SIGKILL);
+    }
+
+    let output = Output {
+        status: exit_status,
+        stdout: stdout_reader.join().unwrap_or_default(),
+        stderr: stderr_reader.join().unwrap_or_default(),
+    };
+    Ok((output, interruption))
+}
+
+fn spawn_pipe_reader<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+fn signal_process_group(pid: u32, signal: libc::c_int) {
+    // A negative pid targets every process in the group
+    unsafe {
+        libc::kill(-(pid as i32), signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(contents: &str, mode: u32) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        fs::set_permissions(file.path(), fs::Permissions::from_mode(mode)).unwrap();
+        file
+    }
+
+    #[test]
+    fn runs_executable_scripts_directly() {
+        let file = script("#!/usr/bin/env python3\nprint(1)\n", 0o755);
+        assert_eq!(default_interpreter(file.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn runs_other_scripts_with_their_shebang() {
+        let file = script("#! /usr/bin/env python3 -u \nprint(1)\n", 0o644);
+        assert_eq!(
+            default_interpreter(file.path()).unwrap().as_deref(),
+            Some("/usr/bin/env python3 -u")
+        );
+    }
+
+    #[test]
+    fn runs_scripts_without_shebang_with_bash() {
+        for contents in ["echo hi\n", "#!\necho hi\n", ""] {
+            let file = script(contents, 0o644);
+            assert_eq!(
+                default_interpreter(file.path()).unwrap().as_deref(),
+                Some("bash")
+            );
+        }
+    }
+
+    #[test]
+    fn splits_interpreters_like_a_shell() {
+        let command = interpreter_command("python3 -W 'ignore::DeprecationWarning' -u").unwrap();
+        assert_eq!(command.get_program(), "python3");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["-W", "ignore::DeprecationWarning", "-u"]
+        );
+        assert!(interpreter_command("python3 '-u").is_err());
+        assert!(interpreter_command(" ").is_err());
+    }
+}
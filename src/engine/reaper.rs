@@ -0,0 +1,179 @@
+use super::lease::release_engine_events;
+use super::run::advance_run;
+use crate::models::{EngineStatus, LightTask, ProcessStatus, TaskStatus};
+use crate::utils::{processing_queue_name, requeue_processing_queue};
+use anyhow::Error as AnyError;
+use bincode::deserialize;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use redis::Commands as RedisCommand;
+
+// An engine is lost once its task process didn't write a heartbeat for this long
+pub const ENGINE_LOST_AFTER_SECONDS: i64 = 30;
+
+pub(crate) enum EngineProcess {
+    Task,
+    Event,
+}
+
+// Writes the heartbeat of a process to its engine row, returns false if the engine was declared
+// lost in the meantime, its tasks were then taken over by another engine
+pub(crate) fn record_heartbeat(
+    conn: &mut PgConnection,
+    engine_uid: i32,
+    process: EngineProcess,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::engines::dsl::*;
+
+    let heartbeat = diesel::update(engines.find(engine_uid));
+    let engine_status: Option<String> = match process {
+        EngineProcess::Task => heartbeat
+            .set(last_heartbeat_at.eq(diesel::dsl::now))
+            .returning(status)
+            .get_result(conn),
+        EngineProcess::Event => heartbeat
+            .set(event_heartbeat_at.eq(diesel::dsl::now))
+            .returning(status)
+            .get_result(conn),
+    }
+    .optional()?;
+    Ok(engine_status != Some(EngineStatus::Lost.to_string()))
+}
+
+// Declares lost the running engines that missed their heartbeats, releases their events and
+// takes over the tasks left in their processing queues. The takeover is done again for every lost
+// engine that has events or tasks left, so a takeover cut short by an error is finished later.
+pub(crate) fn reap_lost_engines(
+    conn: &mut PgConnection,
+    redis_con: &mut redis::Connection,
+    engine_uid: i32,
+) -> Result<(), AnyError> {
+    use crate::schema::engines::dsl::*;
+
+    // Only one engine gets an engine back from the update, the others see it already lost.
+    // An engine whose task process stopped has no tasks to take over, its event process can
+    // still be finishing.
+    let lost_engine_uids: Vec<i32> = diesel::update(engines)
+        .filter(uid.ne(engine_uid))
+        .filter(status.ne_all(vec![
+            EngineStatus::Stopped.to_string(),
+            EngineStatus::Lost.to_string(),
+        ]))
+        .filter(task_process_status.ne(ProcessStatus::Stopped.to_string()))
+        // Engines that never wrote a heartbeat are given the same time from their start
+        .filter(diesel::dsl::sql::<Bool>(&format!(
+            "COALESCE(last_heartbeat_at, started_at) < NOW() - INTERVAL '{} seconds'",
+            ENGINE_LOST_AFTER_SECONDS
+        )))
+        .set((
+            status.eq(EngineStatus::Lost.to_string()),
+            task_process_status.eq(ProcessStatus::Lost.to_string()),
+            event_process_status.eq(ProcessStatus::Lost.to_string()),
+        ))
+        .returning(uid)
+        .get_results(conn)?;
+
+    for lost_engine_uid in lost_engine_uids {
+        println!(
+            "Engine {} missed its heartbeats, declaring it lost",
+            lost_engine_uid
+        );
+    }
+
+    let lost_engine_uids: Vec<i32> = engines
+        .select(uid)
+        .filter(uid.ne(engine_uid))
+        .filter(status.eq(EngineStatus::Lost.to_string()))
+        .load(conn)?;
+    for lost_engine_uid in lost_engine_uids {
+        // Its events are claimed right away instead of once their leases expire
+        let released = release_engine_events(conn, lost_engine_uid)?;
+        if released > 0 {
+            println!(
+                "Released {} events of lost engine {}",
+                released, lost_engine_uid
+            );
+        }
+        let left: usize = redis_con.llen(processing_queue_name(lost_engine_uid))?;
+        if left == 0 {
+            continue;
+        }
+        let failed = fail_exhausted_tasks(conn, redis_con, lost_engine_uid)?;
+        let requeued = requeue_processing_queue(redis_con, lost_engine_uid)?;
+        println!(
+            "Took over the tasks of lost engine {}: {} requeued, {} failed",
+            lost_engine_uid, requeued, failed
+        );
+    }
+    Ok(())
+}
+
+// A task that was running on a lost engine counts as a failed attempt. The ones with attempts
+// left are set to retry, the others are failed and removed from the processing queue.
+// Returns how many tasks were failed.
+fn fail_exhausted_tasks(
+    conn: &mut PgConnection,
+    redis_con: &mut redis::Connection,
+    lost_engine_uid: i32,
+) -> Result<usize, AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    let processing_queue = processing_queue_name(lost_engine_uid);
+    let serialized_tasks: Vec<Vec<u8>> = redis_con.lrange(&processing_queue, 0, -1)?;
+    let mut failed = 0;
+    for serialized_task in serialized_tasks {
+        // Requeued as it is, the engine that picks it up sets it aside
+        let light_task: LightTask = match deserialize(&serialized_task) {
+            Ok(light_task) => light_task,
+            Err(e) => {
+                eprintln!(
+                    "Failed to decode a task of lost engine {} {}",
+                    lost_engine_uid, e
+                );
+                continue;
+            }
+        };
+        // A run picked up again by another engine since is left to it
+        let run_state: Option<(String, i32)> = task_runs
+            .find(light_task.task_run_uid)
+            .filter(engine_uid.eq(lost_engine_uid))
+            .select((status, attempt))
+            .first(conn)
+            .optional()?;
+        // Tasks that didn't start are requeued as they are
+        let Some((current_status, current_attempt)) = run_state else {
+            continue;
+        };
+        if current_status != TaskStatus::Running.to_string() {
+            continue;
+        }
+
+        // Attempts are counted from 1, so the last one is retries + 1
+        if current_attempt <= light_task.retries {
+            diesel::update(task_runs.find(light_task.task_run_uid))
+                .set((
+                    status.eq(TaskStatus::Retrying.to_string()),
+                    updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+            continue;
+        }
+
+        // on_failure isn't run, the output of the attempt was lost with the engine
+        diesel::update(task_runs.find(light_task.task_run_uid))
+            .set((
+                status.eq(TaskStatus::Failed.to_string()),
+                stderr.eq(format!(
+                    "Engine {} was lost while running this task",
+                    lost_engine_uid
+                )),
+                updated_at.eq(diesel::dsl::now),
+                completed_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn)?;
+        advance_run(conn, light_task.task_run_uid)?;
+        redis_con.lrem::<_, _, ()>(&processing_queue, 1, serialized_task)?;
+        failed += 1;
+    }
+    Ok(failed)
+}
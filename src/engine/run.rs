@@ -0,0 +1,349 @@
+use crate::models::{LightTask, NewTaskRun, Task, TaskStatus};
+use crate::schema;
+use crate::utils::{
+    create_redis_connection, push_task_to_queue, push_tasks_to_queue, PAUSED_QUEUE_NAME,
+};
+use anyhow::Error as AnyError;
+use diesel::prelude::*;
+use std::collections::BTreeMap;
+
+// A run is everything triggered by one fire of an event. Every task of the event gets a task run,
+// tasks without dependencies are queued right away and the others wait for their dependencies.
+
+// Task runs ready to be picked up, the ones of paused tasks are parked in the paused queue instead
+pub struct ReadyTasks {
+    queued: Vec<LightTask>,
+    paused: Vec<LightTask>,
+}
+
+impl ReadyTasks {
+    pub fn push(self) -> Result<(), AnyError> {
+        if !self.paused.is_empty() {
+            let mut redis_con = create_redis_connection()?;
+            for light_task in self.paused {
+                push_task_to_queue(&mut redis_con, PAUSED_QUEUE_NAME, &light_task)?;
+            }
+        }
+        push_tasks_to_queue(self.queued)
+    }
+}
+
+/// Inserts the task runs of a fire and returns the ones that are ready. They are to be pushed
+/// once the transaction inserting them is committed, an engine picking one up before that
+/// wouldn't find its task run.
+pub fn start_run(
+    conn: &mut PgConnection,
+    parent_event_uid: i32,
+    parent_run_uid: i32,
+) -> Result<ReadyTasks, AnyError> {
+    use crate::schema::tasks::dsl::*;
+    let event_task_uids: Vec<i32> = tasks
+        .select(uid)
+        .filter(event_uid.eq(parent_event_uid))
+        .order(uid)
+        .load(conn)?;
+
+    let mut ready_task_runs = Vec::new();
+    for task_uid in event_task_uids {
+        let has_dependencies = !dependency_uids(conn, task_uid)?.is_empty();
+        let new_task_run = NewTaskRun {
+            run_uid: parent_run_uid,
+            task_uid,
+            status: if has_dependencies {
+                TaskStatus::Waiting.to_string()
+            } else {
+                TaskStatus::Pending.to_string()
+            },
+        };
+        let task_run_uid = diesel::insert_into(schema::task_runs::table)
+            .values(&new_task_run)
+            .returning(schema::task_runs::uid)
+            .get_result::<i32>(conn)?;
+        if !has_dependencies {
+            ready_task_runs.push((task_uid, task_run_uid));
+        }
+    }
+    ready_tasks(conn, parent_run_uid, ready_task_runs)
+}
+
+/// Called once a task run is finished, a completed task run releases the task runs
+/// whose dependencies have all completed, any other outcome skips the task runs depending on it
+pub fn advance_run(conn: &mut PgConnection, task_run_uid: i32) -> Result<(), AnyError> {
+    use crate::schema::task_runs::dsl::*;
+    let (finished_run_uid, finished_task_uid, finished_status): (Option<i32>, i32, String) =
+        task_runs
+            .find(task_run_uid)
+            .select((run_uid, task_uid, status))
+            .first(conn)?;
+    // Task runs from before runs existed have nothing depending on them
+    let Some(finished_run_uid) = finished_run_uid else {
+        return Ok(());
+    };
+
+    if finished_status != TaskStatus::Completed.to_string() {
+        return skip_dependents(conn, finished_run_uid, finished_task_uid);
+    }
+
+    let mut ready_task_runs = Vec::new();
+    for dependent_uid in dependent_uids(conn, finished_task_uid)? {
+        let dependency_statuses: Vec<String> = task_runs
+            .select(status)
+            .filter(run_uid.eq(finished_run_uid))
+            .filter(task_uid.eq_any(dependency_uids(conn, dependent_uid)?))
+            .load(conn)?;
+        if dependency_statuses
+            .iter()
+            .any(|s| *s != TaskStatus::Completed.to_string())
+        {
+            continue;
+        }
+
+        // Dependencies finishing at the same time can both get here, only one of them moves the
+        // task run out of waiting and queues it
+        let released_task_run_uid: Option<i32> = diesel::update(
+            task_runs
+                .filter(run_uid.eq(finished_run_uid))
+                .filter(task_uid.eq(dependent_uid))
+                .filter(status.eq(TaskStatus::Waiting.to_string())),
+        )
+        .set((
+            status.eq(TaskStatus::Pending.to_string()),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .returning(uid)
+        .get_result(conn)
+        .optional()?;
+        if let Some(released_task_run_uid) = released_task_run_uid {
+            ready_task_runs.push((dependent_uid, released_task_run_uid));
+        }
+    }
+    ready_tasks(conn, finished_run_uid, ready_task_runs)?.push()
+}
+
+/// Skips every waiting task run of the run that depends on the task, directly or not
+pub fn skip_dependents(
+    conn: &mut PgConnection,
+    parent_run_uid: i32,
+    parent_task_uid: i32,
+) -> Result<(), AnyError> {
+    use crate::schema::task_runs::dsl::*;
+    for dependent_uid in dependent_uids(conn, parent_task_uid)? {
+        let skipped_rows = diesel::update(
+            task_runs
+                .filter(run_uid.eq(parent_run_uid))
+                .filter(task_uid.eq(dependent_uid))
+                .filter(status.eq(TaskStatus::Waiting.to_string())),
+        )
+        .set((
+            status.eq(TaskStatus::Skipped.to_string()),
+            updated_at.eq(diesel::dsl::now),
+            completed_at.eq(diesel::dsl::now),
+        ))
+        .execute(conn)?;
+        if skipped_rows > 0 {
+            println!(
+                "Skipping task {} of run {}, a task it depends on didn't complete",
+                dependent_uid, parent_run_uid
+            );
+            skip_dependents(conn, parent_run_uid, dependent_uid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Looks up an output written in this run by another task of the same event, the task is found by name
+pub fn task_output(
+    conn: &mut PgConnection,
+    parent_run_uid: i32,
+    sibling_task_uid: i32,
+    output_task_name: &str,
+    output_key: &str,
+) -> Result<Option<String>, AnyError> {
+    let parent_event_uid: i32 = schema::tasks::table
+        .find(sibling_task_uid)
+        .select(schema::tasks::event_uid)
+        .first(conn)?;
+    let run_outputs: Option<Option<serde_json::Value>> = schema::task_runs::table
+        .inner_join(schema::tasks::table)
+        .filter(schema::task_runs::run_uid.eq(parent_run_uid))
+        .filter(schema::tasks::event_uid.eq(parent_event_uid))
+        .filter(schema::tasks::name.eq(output_task_name))
+        .select(schema::task_runs::outputs)
+        .first(conn)
+        .optional()?;
+    Ok(run_outputs
+        .flatten()
+        .and_then(|outputs| outputs.get(output_key).cloned())
+        .and_then(|value| value.as_str().map(str::to_string)))
+}
+
+// Builds the light tasks of task runs ready to be queued, the task runs of paused tasks are paused
+fn ready_tasks(
+    conn: &mut PgConnection,
+    parent_run_uid: i32,
+    ready_task_runs: Vec<(i32, i32)>,
+) -> Result<ReadyTasks, AnyError> {
+    let mut light_tasks = Vec::new();
+    let mut paused_light_tasks = Vec::new();
+    for (ready_task_uid, task_run_uid) in ready_task_runs {
+        let ready_task: Task = schema::tasks::table
+            .find(ready_task_uid)
+            .select(Task::as_select())
+            .first(conn)?;
+        let ready_script = ready_task
+            .script()
+            .ok_or_else(|| AnyError::msg("Task has nothing to run"))?;
+        let (task_env, task_args) = inherit_env_and_args(
+            event_env_and_args(conn, ready_task.event_uid)?,
+            ready_task.env,
+            ready_task.args,
+        )?;
+        let light_task = LightTask {
+            uid: ready_task_uid,
+            task_run_uid,
+            run_uid: parent_run_uid,
+            script: ready_script,
+            on_failure: ready_task.on_failure,
+            timeout_seconds: ready_task.timeout_seconds,
+            retries: ready_task.retries,
+            retry_delay_seconds: ready_task.retry_delay_seconds,
+            backoff: ready_task.backoff,
+            env: task_env.into_iter().collect(),
+            args: task_args,
+        };
+        if ready_task.status == TaskStatus::Paused.to_string() {
+            diesel::update(schema::task_runs::table.find(task_run_uid))
+                .set(schema::task_runs::status.eq(TaskStatus::Paused.to_string()))
+                .execute(conn)?;
+            paused_light_tasks.push(light_task);
+        } else {
+            light_tasks.push(light_task);
+        }
+    }
+
+    Ok(ReadyTasks {
+        queued: light_tasks,
+        paused: paused_light_tasks,
+    })
+}
+
+/// Environment variables and arguments of the event, on top of the ones of its workflow
+pub fn event_env_and_args(
+    conn: &mut PgConnection,
+    parent_event_uid: i32,
+) -> Result<(BTreeMap<String, String>, Vec<String>), AnyError> {
+    let (parent_workflow_uid, event_env, event_args): (
+        Option<i32>,
+        serde_json::Value,
+        serde_json::Value,
+    ) = schema::events::table
+        .find(parent_event_uid)
+        .select((
+            schema::events::workflow_uid,
+            schema::events::env,
+            schema::events::args,
+        ))
+        .first(conn)?;
+    let mut inherited = (BTreeMap::new(), Vec::new());
+    if let Some(parent_workflow_uid) = parent_workflow_uid {
+        let (workflow_env, workflow_args): (serde_json::Value, serde_json::Value) =
+            schema::workflows::table
+                .find(parent_workflow_uid)
+                .select((schema::workflows::env, schema::workflows::args))
+                .first(conn)?;
+        inherited = inherit_env_and_args(inherited, workflow_env, workflow_args)?;
+    }
+    inherit_env_and_args(inherited, event_env, event_args)
+}
+
+// Variables set at a lower level override the ones with the same name above it,
+// arguments set at a lower level replace all of the ones above it
+fn inherit_env_and_args(
+    (mut inherited_env, inherited_args): (BTreeMap<String, String>, Vec<String>),
+    own_env: serde_json::Value,
+    own_args: serde_json::Value,
+) -> Result<(BTreeMap<String, String>, Vec<String>), AnyError> {
+    inherited_env.extend(serde_json::from_value::<BTreeMap<String, String>>(own_env)?);
+    let own_args: Vec<String> = serde_json::from_value(own_args)?;
+    let args = if own_args.is_empty() {
+        inherited_args
+    } else {
+        own_args
+    };
+    Ok((inherited_env, args))
+}
+
+fn dependency_uids(conn: &mut PgConnection, parent_task_uid: i32) -> Result<Vec<i32>, AnyError> {
+    use crate::schema::task_dependencies::dsl::*;
+    let uids = task_dependencies
+        .select(dependency_uid)
+        .filter(task_uid.eq(parent_task_uid))
+        .load(conn)?;
+    Ok(uids)
+}
+
+fn dependent_uids(conn: &mut PgConnection, parent_task_uid: i32) -> Result<Vec<i32>, AnyError> {
+    use crate::schema::task_dependencies::dsl::*;
+    let uids = task_dependencies
+        .select(task_uid)
+        .filter(dependency_uid.eq(parent_task_uid))
+        .load(conn)?;
+    Ok(uids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Inherits down from the workflow through its event to its task, like tasks are queued
+    fn inherit(
+        levels: [(serde_json::Value, serde_json::Value); 3],
+    ) -> (BTreeMap<String, String>, Vec<String>) {
+        levels
+            .into_iter()
+            .try_fold(Default::default(), |inherited, (own_env, own_args)| {
+                inherit_env_and_args(inherited, own_env, own_args)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn overrides_inherited_variables() {
+        let (env, _) = inherit([
+            (json!({"REGION": "eu", "LEVEL": "workflow"}), json!([])),
+            (json!({"LEVEL": "event"}), json!([])),
+            (json!({"LEVEL": "task", "DEBUG": "1"}), json!([])),
+        ]);
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                ("DEBUG".to_string(), "1".to_string()),
+                ("LEVEL".to_string(), "task".to_string()),
+                ("REGION".to_string(), "eu".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn replaces_inherited_arguments_in_their_order() {
+        let (_, args) = inherit([
+            (json!({}), json!(["--workflow"])),
+            (json!({}), json!(["--event", "b", "a"])),
+            (json!({}), json!([])),
+        ]);
+        assert_eq!(args, ["--event", "b", "a"]);
+
+        let (_, args) = inherit([
+            (json!({}), json!(["--workflow"])),
+            (json!({}), json!([])),
+            (json!({}), json!(["--task", "z"])),
+        ]);
+        assert_eq!(args, ["--task", "z"]);
+    }
+
+    #[test]
+    fn rejects_variables_that_are_not_strings() {
+        assert!(inherit_env_and_args(Default::default(), json!({"PORT": 80}), json!([])).is_err());
+    }
+}
@@ -1,24 +1,53 @@
-use crate::models::{Engine, EngineStatus, LightTask, ProcessStatus, TaskStatus};
-use crate::utils::{self, create_redis_connection, establish_pg_connection};
+use super::process::{
+    effective_timeout, shutdown_grace_period, spawn_script, wait_for_child, Interruption,
+    ScriptChild,
+};
+use super::reaper::{reap_lost_engines, record_heartbeat, EngineProcess};
+use super::run::{advance_run, task_output};
+use crate::models::{Backoff, LightTask, ProcessStatus, Script, TaskStatus};
+use crate::outputs::{interpolate_outputs, parse_output_file};
+use crate::schedule::backoff_delay_seconds;
+use crate::utils::{
+    self, acknowledge_task, bury_task, create_redis_connection, establish_pg_connection,
+    move_due_delayed_tasks, processing_queue_name, push_task_to_delayed_queue, push_task_to_queue,
+    release_task, requeue_processing_queue, PAUSED_QUEUE_NAME,
+};
 use anyhow::Error as AnyError;
 use bincode::deserialize;
 use diesel::prelude::*;
 use rayon::ThreadPoolBuilder;
-use redis::{Commands as RedisCommand, FromRedisValue};
+use redis::{Commands as RedisCommand, Direction};
+use serde_json::Value;
+use std::fs;
+use std::io::Write;
 use std::path::Path;
-use std::process::Command as ShellCommand;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::process::Output;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{str, thread};
+use tempfile::NamedTempFile;
 
 const THREAD_COUNT: usize = 4;
+// Delay between two attempts of a task that doesn't set a retry_delay
+const DEFAULT_RETRY_DELAY_SECONDS: i64 = 10;
+// Environment variables have a size limit, only the end of a long stderr is passed to on_failure
+// and payload values longer than this are only in the payload file
+const ENV_VALUE_LIMIT: usize = 32 * 1024;
+const FINISHED_STATUSES: [TaskStatus; 4] = [
+    TaskStatus::Completed,
+    TaskStatus::Failed,
+    TaskStatus::Skipped,
+    TaskStatus::TimedOut,
+];
 
 pub fn queue_processor(running: Arc<AtomicBool>, engine_uid: i32) -> Result<(), AnyError> {
     let thread_pool = ThreadPoolBuilder::new().num_threads(THREAD_COUNT).build()?;
     let pg_conn = &mut establish_pg_connection();
     let mut redis_con = create_redis_connection()?;
 
+    recover_dead_engine_tasks(pg_conn, &mut redis_con, engine_uid)?;
+
     use crate::schema::engines::dsl::*;
 
     diesel::update(engines)
@@ -26,39 +55,128 @@ pub fn queue_processor(running: Arc<AtomicBool>, engine_uid: i32) -> Result<(),
         .set(task_process_status.eq(ProcessStatus::Running.to_string()))
         .execute(pg_conn)?;
 
+    let processing_queue = processing_queue_name(engine_uid);
+    // Tasks picked up by this engine that haven't finished yet
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    // Set once the grace period of a shutdown is over, the tasks still running are then killed
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let mut draining = false;
     while running.load(Ordering::SeqCst) {
-        let task: Option<redis::Value> = redis_con.lpop(utils::QUEUE_NAME, Default::default())?;
-        match task {
-            Some(value) => {
-                let popped_value: String = FromRedisValue::from_redis_value(&value)?;
-                // If the program exists, then thread_pool will be dropped and all threads will be stopped
-                // which means that threads will not be able to complete their current task
-                thread_pool.spawn(move || {
-                    let task: LightTask = deserialize(popped_value.as_bytes()).unwrap();
-                    println!("Task: {}", task);
-                    if let Err(e) = execute_task(task) {
-                        println!("Failed to execute task {}", e);
-                    };
-                });
-            }
-            None => {
-                println!("No task to process");
+        // An engine declared lost leaves its tasks to the engine that took them over
+        if !record_heartbeat(pg_conn, engine_uid, EngineProcess::Task)? {
+            println!("Engine {} was declared lost, stopping", engine_uid);
+            interrupt_tasks(&in_flight, &shutting_down);
+            return Ok(());
+        }
+        if let Err(e) = reap_lost_engines(pg_conn, &mut redis_con, engine_uid) {
+            eprintln!("Failed to reap lost engines {}", e);
+        }
+
+        let moved = move_due_delayed_tasks(&mut redis_con)?;
+        if moved > 0 {
+            println!("Queued {} tasks due for their next attempt", moved);
+        }
+
+        // Engines sharing a redis pull from the same queue, an engine only takes as many tasks as
+        // it has free threads, so the tasks a busy engine can't run are left to the idle ones
+        let mut picked_up = 0;
+        while !draining && in_flight.load(Ordering::SeqCst) < THREAD_COUNT {
+            // The task stays in the processing queue of the engine until it is acknowledged,
+            // so a task of a process that dies midway is requeued instead of lost
+            let task: Option<Vec<u8>> = redis_con.lmove(
+                utils::QUEUE_NAME,
+                &processing_queue,
+                Direction::Left,
+                Direction::Right,
+            )?;
+            let Some(popped_value) = task else {
+                break;
+            };
+            let task: LightTask = match deserialize(&popped_value) {
+                Ok(task) => task,
+                Err(e) => {
+                    eprintln!(
+                        "Moving a task that can't be decoded to the dead tasks {}",
+                        e
+                    );
+                    bury_task(&mut redis_con, engine_uid, &popped_value)?;
+                    continue;
+                }
+            };
+            // The task is given back to the queue, it is picked up again on a later loop
+            if let Err(e) = record_pick_up(pg_conn, engine_uid, task.task_run_uid) {
+                eprintln!(
+                    "Failed to record the pick up of task run {} {}",
+                    task.task_run_uid, e
+                );
+                release_task(&mut redis_con, engine_uid, &popped_value)?;
+                break;
             }
+            picked_up += 1;
+            in_flight.fetch_add(1, Ordering::SeqCst);
+            let in_flight = in_flight.clone();
+            let shutting_down = shutting_down.clone();
+            // If the process dies, the tasks its threads were running are requeued by the next
+            // engine that starts, a process that stops waits for them instead
+            thread_pool.spawn(move || {
+                println!("Task: {}", task);
+                let task_run_uid = task.task_run_uid;
+                // A task the engine fails to execute is failed rather than left running,
+                // so the tasks depending on it aren't left waiting
+                let executed = execute_task(task, engine_uid, &shutting_down).or_else(|e| {
+                    println!("Failed to execute task {}", e);
+                    fail_task_run(&mut establish_pg_connection(), task_run_uid, &e)
+                });
+                match executed {
+                    Ok(()) => {
+                        let acknowledged =
+                            create_redis_connection().map_err(AnyError::from).and_then(
+                                |mut con| acknowledge_task(&mut con, engine_uid, &popped_value),
+                            );
+                        if let Err(e) = acknowledged {
+                            println!("Failed to acknowledge task {}", e);
+                        }
+                    }
+                    // Left in the processing queue, the task is requeued when the process restarts
+                    Err(e) => println!(
+                        "Failed to record the failure of task run {} {}",
+                        task_run_uid, e
+                    ),
+                }
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        if picked_up == 0 && !draining {
+            println!("No task to process");
         }
 
         // rust couldn't infer the type of received_stop_signal_result
-        let received_stop_signal_result: Result<Option<bool>, _> = engines
+        let received_stop_signal_result: Result<Option<(bool, bool)>, _> = engines
             .find(engine_uid)
-            .select(stop_signal)
+            .select((stop_signal, drain_signal))
             .first(pg_conn)
             .optional();
         match received_stop_signal_result {
-            Ok(Some(signal_on)) => {
-                if signal_on {
-                    println!("Received stop signal");
+            Ok(Some((true, false))) => {
+                println!("Received stop signal");
+                break;
+            }
+            // A draining engine takes no new tasks and stops once its running tasks are done
+            Ok(Some((true, true))) => {
+                let running_tasks = in_flight.load(Ordering::SeqCst);
+                if running_tasks == 0 {
+                    println!("Drained, stopping");
                     break;
                 }
+                if !draining {
+                    println!(
+                        "Received drain signal, waiting for {} running tasks",
+                        running_tasks
+                    );
+                    draining = true;
+                }
             }
+            Ok(Some(_)) => {}
             Ok(None) => {
                 println!("No stop signal");
             }
@@ -76,6 +194,34 @@ pub fn queue_processor(running: Arc<AtomicBool>, engine_uid: i32) -> Result<(),
         println!("\nCtrl+C signal detected. Exiting...");
     }
 
+    // No new task is taken from here on. The running tasks get the grace period to finish,
+    // the ones still running after it are killed and queued again as interrupted.
+    let grace_period = shutdown_grace_period()?;
+    if in_flight.load(Ordering::SeqCst) > 0 {
+        println!(
+            "Waiting up to {}s for {} running tasks to finish",
+            grace_period.as_secs(),
+            in_flight.load(Ordering::SeqCst)
+        );
+    }
+    let grace_started = Instant::now();
+    while in_flight.load(Ordering::SeqCst) > 0 {
+        if grace_started.elapsed() >= grace_period && !shutting_down.load(Ordering::SeqCst) {
+            println!(
+                "Interrupting {} tasks still running after the grace period",
+                in_flight.load(Ordering::SeqCst)
+            );
+            shutting_down.store(true, Ordering::SeqCst);
+        }
+        // The engine would be declared lost by the others during a long grace period otherwise
+        if !record_heartbeat(pg_conn, engine_uid, EngineProcess::Task)? {
+            println!("Engine {} was declared lost, stopping", engine_uid);
+            interrupt_tasks(&in_flight, &shutting_down);
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+
     diesel::update(engines)
         .filter(uid.eq(engine_uid))
         .set(task_process_status.eq(ProcessStatus::Stopped.to_string()))
@@ -83,62 +229,342 @@ pub fn queue_processor(running: Arc<AtomicBool>, engine_uid: i32) -> Result<(),
     Ok(())
 }
 
-fn execute_task(task: LightTask) -> Result<(), AnyError> {
+// Kills the running tasks and waits for their threads to be done with them. The tasks of a lost
+// engine are run again by the engine that took them over, they would run twice otherwise.
+fn interrupt_tasks(in_flight: &AtomicUsize, shutting_down: &AtomicBool) {
+    shutting_down.store(true, Ordering::SeqCst);
+    while in_flight.load(Ordering::SeqCst) > 0 {
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+// Requeues the tasks left in the processing queue of this engine by a previous run of the process,
+// and the ones of engines whose task process is no longer running
+fn recover_dead_engine_tasks(
+    conn: &mut PgConnection,
+    redis_con: &mut redis::Connection,
+    engine_uid: i32,
+) -> Result<(), AnyError> {
+    use crate::schema::engines::dsl::*;
+
+    let dead_engine_uids: Vec<i32> = engines
+        .select(uid)
+        .filter(
+            uid.eq(engine_uid)
+                .or(task_process_status.ne(ProcessStatus::Running.to_string())),
+        )
+        .load(conn)?;
+    for dead_engine_uid in dead_engine_uids {
+        let requeued = requeue_processing_queue(redis_con, dead_engine_uid)?;
+        if requeued > 0 {
+            println!(
+                "Requeued {} unfinished tasks of engine {}",
+                requeued, dead_engine_uid
+            );
+        }
+    }
+    Ok(())
+}
+
+// Records which engine took a task run from the queue and when. Paused, aborted and finished runs
+// are only passed along by the engine, they keep the engine that ran them.
+fn record_pick_up(
+    conn: &mut PgConnection,
+    picking_engine_uid: i32,
+    task_run_uid: i32,
+) -> Result<(), AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    diesel::update(task_runs.find(task_run_uid))
+        .filter(status.eq_any(vec![
+            TaskStatus::Pending.to_string(),
+            TaskStatus::Running.to_string(),
+            TaskStatus::Retrying.to_string(),
+            TaskStatus::Interrupted.to_string(),
+        ]))
+        .set((
+            engine_uid.eq(picking_engine_uid),
+            picked_up_at.eq(diesel::dsl::now),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+// Fails a task run the engine couldn't execute with the error in its stderr, then queues or skips
+// the tasks depending on it. Runs that are paused, aborted or already finished are left as they are.
+fn fail_task_run(
+    conn: &mut PgConnection,
+    task_run_uid: i32,
+    error: &AnyError,
+) -> Result<(), AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    diesel::update(task_runs.find(task_run_uid))
+        .filter(status.eq_any(vec![
+            TaskStatus::Pending.to_string(),
+            TaskStatus::Running.to_string(),
+            TaskStatus::Retrying.to_string(),
+            TaskStatus::Interrupted.to_string(),
+        ]))
+        .set((
+            status.eq(TaskStatus::Failed.to_string()),
+            stderr.eq(format!("The engine failed to execute this task: {}", error)),
+            updated_at.eq(diesel::dsl::now),
+            completed_at.eq(diesel::dsl::now),
+        ))
+        .execute(conn)?;
+    // The run can also have been finished before the error, its dependents are advanced either way
+    let current_status: Option<String> = task_runs
+        .find(task_run_uid)
+        .select(status)
+        .first(conn)
+        .optional()?;
+    if current_status.is_some_and(|current_status| {
+        FINISHED_STATUSES
+            .iter()
+            .any(|finished| current_status == finished.to_string())
+    }) {
+        advance_run(conn, task_run_uid)?;
+    }
+    Ok(())
+}
+
+fn execute_task(
+    task: LightTask,
+    executing_engine_uid: i32,
+    shutting_down: &AtomicBool,
+) -> Result<(), AnyError> {
     println!("Task Executor");
 
-    use crate::schema::tasks::dsl::*;
+    use crate::schema::task_runs::dsl::*;
     let conn = &mut establish_pg_connection();
-    // todo , update task status to running
 
-    diesel::update(tasks.find(task.uid))
+    // The task run could have been paused or aborted while it was waiting in the queue,
+    // or purged along with its workflow
+    let current_status: String = match task_runs
+        .find(task.task_run_uid)
+        .select(status)
+        .first(conn)
+        .optional()?
+    {
+        Some(current_status) => current_status,
+        None => {
+            println!(
+                "Task run {} no longer exists, skipping it",
+                task.task_run_uid
+            );
+            return Ok(());
+        }
+    };
+    if current_status == TaskStatus::Paused.to_string() {
+        println!(
+            "Task run {} is paused, moving it to the paused queue",
+            task.task_run_uid
+        );
+        let mut redis_con = create_redis_connection()?;
+        return push_task_to_queue(&mut redis_con, PAUSED_QUEUE_NAME, &task);
+    }
+    if current_status == TaskStatus::Aborted.to_string() {
+        println!("Task run {} was aborted, skipping it", task.task_run_uid);
+        return Ok(());
+    }
+    // A requeued task can already be finished if its process died before acknowledging it,
+    // the tasks depending on it may not have been queued yet
+    if FINISHED_STATUSES
+        .iter()
+        .any(|finished| current_status == finished.to_string())
+    {
+        println!(
+            "Task run {} is already finished, not running it again",
+            task.task_run_uid
+        );
+        return advance_run(conn, task.task_run_uid);
+    }
+    if current_status == TaskStatus::Running.to_string()
+        || current_status == TaskStatus::Interrupted.to_string()
+    {
+        println!(
+            "Task run {} was interrupted, running it again",
+            task.task_run_uid
+        );
+    }
+
+    // A task run coming back from the delayed tasks starts its next attempt
+    let attempt_increment = i32::from(current_status == TaskStatus::Retrying.to_string());
+    let current_attempt: i32 = diesel::update(task_runs.find(task.task_run_uid))
         .set((
             status.eq(TaskStatus::Running.to_string()),
             updated_at.eq(diesel::dsl::now),
+            started_at.eq(diesel::dsl::now),
+            attempt.eq(attempt + attempt_increment),
         ))
-        .execute(conn)?;
+        .returning(attempt)
+        .get_result(conn)?;
 
-    let path_basename = match Path::new(&task.path).file_name() {
-        Some(basename) => basename,
-        None => return Err(AnyError::msg("Failed to get path basename")),
-    };
-    let path_dirname = Path::new(&task.path).parent().unwrap();
+    let attempted = run_task_attempt(
+        conn,
+        &task,
+        executing_engine_uid,
+        current_attempt,
+        shutting_down,
+    );
+    // A run left running would keep the tasks depending on it waiting
+    if let Err(e) = attempted {
+        println!("Task run {} failed to run {}", task.task_run_uid, e);
+        fail_task_run(conn, task.task_run_uid, &e)?;
+    }
+    Ok(())
+}
 
-    let output = ShellCommand::new("bash")
-        .arg(path_basename)
-        .current_dir(path_dirname)
-        .output()
-        .expect("failed to execute process");
+// Runs an attempt of a task that was set to running and records its outcome
+fn run_task_attempt(
+    conn: &mut PgConnection,
+    task: &LightTask,
+    executing_engine_uid: i32,
+    current_attempt: i32,
+    shutting_down: &AtomicBool,
+) -> Result<(), AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    let timeout = effective_timeout(task.timeout_seconds)?;
+    // The files handed to the task are only readable by the engine user and under names nobody
+    // can guess, they are removed once the attempt is over, whatever its outcome
+    let mut payload_file = tempfile::Builder::new()
+        .prefix("workflow_payload_")
+        .tempfile()?;
+    let mut envs = payload_envs(conn, task, &mut payload_file)?;
+    // The task writes its outputs to this file, they are stored with the task run once it exits
+    let output_file = tempfile::Builder::new()
+        .prefix("workflow_output_")
+        .tempfile()?;
+    envs.push((
+        "WORKFLOW_OUTPUT".to_string(),
+        output_file.path().to_string_lossy().to_string(),
+    ));
+    for (key, value) in &task.env {
+        envs.push((key.clone(), interpolate_task_outputs(conn, task, value)?));
+    }
+    let args = task
+        .args
+        .iter()
+        .map(|arg| interpolate_task_outputs(conn, task, arg))
+        .collect::<Result<Vec<String>, AnyError>>()?;
 
-    if output.status.code().unwrap() == 0 {
-        diesel::update(tasks.find(task.uid))
+    let child = spawn_script(&task.script, &args, &envs)?;
+    let (output, interruption) =
+        wait_for_task_child(conn, task.task_run_uid, child, timeout, shutting_down)?;
+    store_task_outputs(conn, task.task_run_uid, output_file.path())?;
+
+    // The attempt doesn't count, the run is queued again for the next engine to pick it up
+    if interruption == Some(Interruption::Interrupted) {
+        // The run of a lost engine was already taken over by another engine
+        let interrupted = diesel::update(task_runs.find(task.task_run_uid))
+            .filter(status.eq(TaskStatus::Running.to_string()))
+            .filter(engine_uid.eq(executing_engine_uid))
             .set((
-                status.eq(TaskStatus::Completed.to_string()),
+                status.eq(TaskStatus::Interrupted.to_string()),
                 updated_at.eq(diesel::dsl::now),
-                completed_at.eq(diesel::dsl::now),
+                stdout.eq(str::from_utf8(&output.stdout)?),
+                stderr.eq(str::from_utf8(&output.stderr)?),
             ))
             .execute(conn)?;
+        if interrupted == 0 {
+            println!(
+                "Task run {} was interrupted, it was taken over by another engine",
+                task.task_run_uid
+            );
+            return Ok(());
+        }
+        println!(
+            "Task run {} was interrupted by the engine shutting down, queueing it again",
+            task.task_run_uid
+        );
+        let mut redis_con = create_redis_connection()?;
+        return push_task_to_queue(&mut redis_con, utils::QUEUE_NAME, task);
+    }
 
-        // TODO run on failure
+    let failed = interruption != Some(Interruption::Aborted) && !output.status.success();
+    // Attempts are counted from 1, so the last one is retries + 1
+    let will_retry = failed && current_attempt <= task.retries;
+    let outcome = if interruption == Some(Interruption::Aborted) {
+        None
+    } else if output.status.success() {
+        Some(TaskStatus::Completed)
+    } else if will_retry {
+        Some(TaskStatus::Retrying)
+    } else if interruption == Some(Interruption::TimedOut) {
+        Some(TaskStatus::TimedOut)
     } else {
-        diesel::update(tasks.find(task.uid))
-            .set((
-                status.eq(TaskStatus::Failed.to_string()),
-                updated_at.eq(diesel::dsl::now),
-            ))
-            .execute(conn)?;
+        Some(TaskStatus::Failed)
+    };
+    match outcome {
+        // The run only completes with its last attempt
+        Some(TaskStatus::Retrying) => {
+            diesel::update(task_runs.find(task.task_run_uid))
+                .set((
+                    status.eq(TaskStatus::Retrying.to_string()),
+                    updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+        }
+        Some(outcome) => {
+            diesel::update(task_runs.find(task.task_run_uid))
+                .set((
+                    status.eq(outcome.to_string()),
+                    updated_at.eq(diesel::dsl::now),
+                    completed_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+        }
+        // The status was already set to aborted by whoever requested it
+        None => {
+            println!("Task run {} was aborted while running", task.task_run_uid);
+            // Nothing is left to record or advance for a run purged along with its workflow
+            let run_exists = task_runs
+                .find(task.task_run_uid)
+                .count()
+                .get_result::<i64>(conn)?
+                > 0;
+            if !run_exists {
+                return Ok(());
+            }
+        }
     }
 
     // Write stdout and stderr to the database
-    diesel::update(tasks.find(task.uid))
+    diesel::update(task_runs.find(task.task_run_uid))
         .set((
             stdout.eq(str::from_utf8(&output.stdout)?),
             stderr.eq(str::from_utf8(&output.stderr)?),
         ))
         .execute(conn)?;
 
+    if will_retry {
+        let due_at = chrono::Utc::now().naive_utc() + retry_delay(task, current_attempt);
+        println!(
+            "Task run {} failed attempt {} of {}, retrying it at {}",
+            task.task_run_uid,
+            current_attempt,
+            task.retries + 1,
+            due_at
+        );
+        let mut redis_con = create_redis_connection()?;
+        return push_task_to_delayed_queue(&mut redis_con, task, due_at);
+    }
+
+    // A timed out task counts as failed, on_failure only runs once no attempt is left
+    if failed {
+        if let Some(on_failure_path) = &task.on_failure {
+            run_on_failure(conn, task, on_failure_path, &output, timeout, shutting_down)?;
+        }
+    }
+
+    // Queues the tasks that were waiting on this one, or skips them if it didn't complete
+    advance_run(conn, task.task_run_uid)?;
+
     println!(
-        "task id: {} , path: {}\nFinished executing with a status: {}",
-        task.uid, task.path, output.status
+        "task id: {} , run: {} , script: {}\nFinished executing with a status: {}",
+        task.uid, task.run_uid, task.script, output.status
     );
     println!("##############################################");
     println!("stdout: {}", str::from_utf8(&output.stdout)?);
@@ -147,3 +573,276 @@ fn execute_task(task: LightTask) -> Result<(), AnyError> {
     println!("##############################################");
     Ok(())
 }
+
+fn retry_delay(task: &LightTask, failed_attempt: i32) -> chrono::Duration {
+    let base_delay_seconds = task
+        .retry_delay_seconds
+        .unwrap_or(DEFAULT_RETRY_DELAY_SECONDS);
+    let backoff = task.backoff.parse::<Backoff>().unwrap_or(Backoff::Fixed);
+    chrono::Duration::seconds(backoff_delay_seconds(
+        &backoff,
+        base_delay_seconds,
+        failed_attempt,
+    ))
+}
+
+// Tasks get the payload of the event fire that started their run in the file named by
+// WORKFLOW_EVENT_PAYLOAD, the top level keys of a JSON object payload are also set as
+// WORKFLOW_EVENT_PAYLOAD_<KEY> variables
+fn payload_envs(
+    conn: &mut PgConnection,
+    task: &LightTask,
+    payload_file: &mut NamedTempFile,
+) -> Result<Vec<(String, String)>, AnyError> {
+    use crate::schema::event_runs::dsl::*;
+
+    let run_payload: Option<String> = event_runs
+        .find(task.run_uid)
+        .select(payload)
+        .first(conn)
+        .optional()?
+        .flatten();
+    payload_file.write_all(run_payload.as_deref().unwrap_or_default().as_bytes())?;
+
+    let mut envs = vec![
+        (
+            "WORKFLOW_EVENT_PAYLOAD".to_string(),
+            payload_file.path().to_string_lossy().to_string(),
+        ),
+        ("WORKFLOW_RUN_UID".to_string(), task.run_uid.to_string()),
+    ];
+    if let Some(run_payload) = &run_payload {
+        envs.extend(payload_field_envs(run_payload));
+    }
+    Ok(envs)
+}
+
+// WORKFLOW_EVENT_PAYLOAD_<KEY> variables of the top level keys of a JSON object payload, values
+// too large for an environment variable are only in the payload file
+fn payload_field_envs(run_payload: &str) -> Vec<(String, String)> {
+    let mut envs = Vec::new();
+    if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(run_payload) {
+        for (key, value) in fields {
+            let value = match value {
+                Value::String(text) => text,
+                other => other.to_string(),
+            };
+            if value.len() > ENV_VALUE_LIMIT {
+                continue;
+            }
+            let env_key: String = key
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() {
+                        c.to_ascii_uppercase()
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            envs.push((format!("WORKFLOW_EVENT_PAYLOAD_{}", env_key), value));
+        }
+    }
+    envs
+}
+
+// Replaces `${{ tasks.<name>.outputs.<key> }}` with the output of that task in the same run,
+// an output that wasn't written is replaced with an empty string
+fn interpolate_task_outputs(
+    conn: &mut PgConnection,
+    task: &LightTask,
+    text: &str,
+) -> Result<String, AnyError> {
+    interpolate_outputs(text, |reference| {
+        let value = task_output(
+            conn,
+            task.run_uid,
+            task.uid,
+            &reference.task_name,
+            &reference.key,
+        )?;
+        if value.is_none() {
+            println!(
+                "Task run {} uses output {} of task {} which wasn't written",
+                task.task_run_uid, reference.key, reference.task_name
+            );
+        }
+        Ok(value.unwrap_or_default())
+    })
+}
+
+fn store_task_outputs(
+    conn: &mut PgConnection,
+    task_run_uid: i32,
+    output_path: &Path,
+) -> Result<(), AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    let contents = fs::read_to_string(output_path)?;
+    let task_outputs = match parse_output_file(&contents) {
+        Ok(task_outputs) => task_outputs,
+        Err(e) => {
+            println!("Ignoring the outputs of task run {}, {}", task_run_uid, e);
+            return Ok(());
+        }
+    };
+    if task_outputs.is_empty() {
+        return Ok(());
+    }
+    let outputs_object: serde_json::Map<String, Value> = task_outputs
+        .into_iter()
+        .map(|(key, value)| (key, Value::String(value)))
+        .collect();
+    diesel::update(task_runs.find(task_run_uid))
+        .set(outputs.eq(Value::Object(outputs_object)))
+        .execute(conn)?;
+    Ok(())
+}
+
+// The failure handler gets the details of the failed task through environment variables
+fn run_on_failure(
+    conn: &mut PgConnection,
+    task: &LightTask,
+    on_failure_path: &str,
+    task_output: &Output,
+    timeout: Option<Duration>,
+    shutting_down: &AtomicBool,
+) -> Result<(), AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    println!(
+        "Running on_failure handler {} for task run {}",
+        on_failure_path, task.task_run_uid
+    );
+
+    let task_stderr = String::from_utf8_lossy(&task_output.stderr);
+    let envs = [
+        ("WORKFLOW_FAILED_TASK_UID", task.uid.to_string()),
+        ("WORKFLOW_RUN_UID", task.run_uid.to_string()),
+        (
+            "WORKFLOW_FAILED_TASK_EXIT_CODE",
+            task_output
+                .status
+                .code()
+                .map_or("".to_string(), |code| code.to_string()),
+        ),
+        (
+            "WORKFLOW_FAILED_TASK_STDERR",
+            tail_of(&task_stderr, ENV_VALUE_LIMIT).to_string(),
+        ),
+    ];
+
+    let child = spawn_script(&Script::from_path(on_failure_path), &[], &envs)?;
+    // The handler gets the same time as the task it handles
+    let (output, _) = wait_for_task_child(conn, task.task_run_uid, child, timeout, shutting_down)?;
+
+    let handler_status = if output.status.success() {
+        TaskStatus::Completed
+    } else {
+        TaskStatus::Failed
+    };
+    diesel::update(task_runs.find(task.task_run_uid))
+        .set((
+            on_failure_status.eq(handler_status.to_string()),
+            on_failure_stdout.eq(str::from_utf8(&output.stdout)?),
+            on_failure_stderr.eq(str::from_utf8(&output.stderr)?),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(conn)?;
+
+    println!(
+        "on_failure handler of task run {} finished with a status: {}",
+        task.task_run_uid, output.status
+    );
+    Ok(())
+}
+
+// Keeps at most the last `limit` bytes of the text, without splitting a character
+fn tail_of(text: &str, limit: usize) -> &str {
+    if text.len() <= limit {
+        return text;
+    }
+    let mut start = text.len() - limit;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+// Waits for the child while watching the task run status, the child is killed if the run gets
+// aborted, or if the engine is shutting down and its grace period is over
+fn wait_for_task_child(
+    conn: &mut PgConnection,
+    task_run_uid: i32,
+    child: ScriptChild,
+    timeout: Option<Duration>,
+    shutting_down: &AtomicBool,
+) -> Result<(Output, Option<Interruption>), AnyError> {
+    use crate::schema::task_runs::dsl::*;
+
+    let mut interrupted = false;
+    // A run purged along with its workflow is gone, it is killed like an aborted one
+    let (output, interruption) = wait_for_child(child, timeout, || {
+        if shutting_down.load(Ordering::SeqCst) {
+            interrupted = true;
+            return Ok(true);
+        }
+        let current_status: Option<String> = task_runs
+            .find(task_run_uid)
+            .select(status)
+            .first(conn)
+            .optional()?;
+        Ok(current_status
+            .is_none_or(|current_status| current_status == TaskStatus::Aborted.to_string()))
+    })?;
+    if interrupted && interruption == Some(Interruption::Aborted) {
+        return Ok((output, Some(Interruption::Interrupted)));
+    }
+    Ok((output, interruption))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_variables_of_json_object_payloads() {
+        let envs = payload_field_envs(r#"{"city": "Oslo", "max-temp": 21.5, "tags": ["a"]}"#);
+        assert_eq!(
+            envs,
+            [
+                (
+                    "WORKFLOW_EVENT_PAYLOAD_CITY".to_string(),
+                    "Oslo".to_string()
+                ),
+                (
+                    "WORKFLOW_EVENT_PAYLOAD_MAX_TEMP".to_string(),
+                    "21.5".to_string()
+                ),
+                (
+                    "WORKFLOW_EVENT_PAYLOAD_TAGS".to_string(),
+                    r#"["a"]"#.to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn sets_no_variables_of_other_payloads() {
+        assert!(payload_field_envs("plain text").is_empty());
+        assert!(payload_field_envs("[1, 2]").is_empty());
+        assert!(payload_field_envs("").is_empty());
+    }
+
+    #[test]
+    fn leaves_out_values_too_large_for_a_variable() {
+        let payload = serde_json::json!({
+            "small": "x",
+            "large": "x".repeat(ENV_VALUE_LIMIT + 1),
+        });
+        assert_eq!(
+            payload_field_envs(&payload.to_string()),
+            [("WORKFLOW_EVENT_PAYLOAD_SMALL".to_string(), "x".to_string())]
+        );
+    }
+}
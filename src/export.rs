@@ -0,0 +1,163 @@
+use crate::models::{Backoff, Event, Task, Workflow};
+use crate::parser::{
+    ParsableEvent, ParsableTask, Workflow as WorkflowFile, WorkflowFormat, WORKFLOW_FORMAT_VERSION,
+};
+use anyhow::{anyhow, Error as AnyError, Result};
+use diesel::prelude::*;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Rebuilds the definition file of a workflow from what is stored in the database.
+/// Script paths are written relative to the directory the workflow was added from.
+pub fn export_workflow(
+    conn: &mut PgConnection,
+    workflow_uid: i32,
+    format: WorkflowFormat,
+) -> Result<String, AnyError> {
+    let (workflow_file, _) = rebuild_workflow(conn, workflow_uid)?;
+    format.serialize(&workflow_file)
+}
+
+/// Rebuilds a workflow from what is stored in the database, along with the directory it was added
+/// from, if it has events to tell
+pub fn rebuild_workflow(
+    conn: &mut PgConnection,
+    workflow_uid: i32,
+) -> Result<(WorkflowFile, Option<PathBuf>), AnyError> {
+    let workflow: Workflow = crate::schema::workflows::table
+        .find(workflow_uid)
+        .select(Workflow::as_select())
+        .first(conn)?;
+    if workflow.deleted_at.is_some() {
+        return Err(anyhow!("Workflow {} is deleted", workflow_uid));
+    }
+    let events: Vec<Event> = crate::schema::events::table
+        .filter(crate::schema::events::workflow_uid.eq(workflow_uid))
+        .order(crate::schema::events::uid)
+        .select(Event::as_select())
+        .load(conn)?;
+
+    let workflow_path = events
+        .iter()
+        .find_map(|event| event.working_directory.as_ref().map(PathBuf::from));
+    let mut parsable_events = Vec::new();
+    for event in events {
+        let tasks: Vec<Task> = crate::schema::tasks::table
+            .filter(crate::schema::tasks::event_uid.eq(event.uid))
+            .order(crate::schema::tasks::uid)
+            .select(Task::as_select())
+            .load(conn)?;
+        let parsable_tasks = tasks
+            .iter()
+            .map(|task| export_task(conn, task, &tasks))
+            .collect::<Result<Vec<_>, AnyError>>()?;
+        parsable_events.push(export_event(event, parsable_tasks)?);
+    }
+
+    let workflow_file = WorkflowFile {
+        version: Some(WORKFLOW_FORMAT_VERSION),
+        name: workflow.name,
+        description: workflow.description,
+        env: env_from_value(workflow.env)?,
+        args: args_from_value(workflow.args)?,
+        events: parsable_events,
+    };
+    Ok((workflow_file, workflow_path))
+}
+
+fn export_event(event: Event, tasks: Vec<ParsableTask>) -> Result<ParsableEvent, AnyError> {
+    let is_script_triggered =
+        event.trigger.is_some() || event.command.is_some() || event.run.is_some();
+    let working_directory = event.working_directory.as_deref();
+    Ok(ParsableEvent {
+        name: event.name,
+        description: event.description,
+        trigger: event
+            .trigger
+            .map(|trigger| relative_path(&trigger, working_directory)),
+        command: event.command,
+        run: event.run,
+        interpreter: event.interpreter,
+        schedule: event.schedule,
+        timezone: event.timezone,
+        every: event.every_seconds.map(format_seconds),
+        mode: Some(event.mode),
+        cooldown: event.cooldown_seconds.map(format_seconds),
+        timeout: event.timeout_seconds.map(format_seconds),
+        poll_interval: event.poll_interval_seconds.map(format_seconds),
+        max_attempts: event.max_attempts.map(u32::try_from).transpose()?,
+        // Polling settings are only allowed on script triggered events
+        backoff: Some(event.backoff).filter(|_| is_script_triggered),
+        env: env_from_value(event.env)?,
+        args: args_from_value(event.args)?,
+        tasks,
+    })
+}
+
+fn export_task(
+    conn: &mut PgConnection,
+    task: &Task,
+    event_tasks: &[Task],
+) -> Result<ParsableTask, AnyError> {
+    let dependency_uids: Vec<i32> = crate::schema::task_dependencies::table
+        .filter(crate::schema::task_dependencies::task_uid.eq(task.uid))
+        .select(crate::schema::task_dependencies::dependency_uid)
+        .load(conn)?;
+    let names_by_uid: HashMap<i32, &str> = event_tasks
+        .iter()
+        .filter_map(|t| t.name.as_deref().map(|name| (t.uid, name)))
+        .collect();
+    let depends_on: Vec<String> = dependency_uids
+        .iter()
+        .filter_map(|uid| names_by_uid.get(uid).map(|name| name.to_string()))
+        .collect();
+
+    let working_directory = task.working_directory.as_deref();
+    Ok(ParsableTask {
+        name: task.name.clone(),
+        description: task.description.clone(),
+        path: task
+            .path
+            .as_ref()
+            .map(|path| relative_path(path, working_directory)),
+        command: task.command.clone(),
+        run: task.run.clone(),
+        interpreter: task.interpreter.clone(),
+        on_failure: task
+            .on_failure
+            .as_ref()
+            .map(|path| relative_path(path, working_directory)),
+        depends_on: Some(depends_on).filter(|names| !names.is_empty()),
+        timeout: task.timeout_seconds.map(format_seconds),
+        retries: Some(u32::try_from(task.retries)?).filter(|retries| *retries > 0),
+        retry_delay: task.retry_delay_seconds.map(format_seconds),
+        backoff: Some(task.backoff.clone()).filter(|b| *b != Backoff::Fixed.to_string()),
+        env: env_from_value(task.env.clone())?,
+        args: args_from_value(task.args.clone())?,
+    })
+}
+
+// Paths are stored joined with the directory of the workflow file
+fn relative_path(path: &str, working_directory: Option<&str>) -> String {
+    working_directory
+        .and_then(|directory| Path::new(path).strip_prefix(directory).ok())
+        .map(|relative| format!("./{}", relative.display()))
+        .unwrap_or_else(|| path.to_string())
+}
+
+fn format_seconds(seconds: i64) -> String {
+    humantime::format_duration(Duration::from_secs(seconds.max(0) as u64)).to_string()
+}
+
+// Empty env and args are left out of the file
+fn env_from_value(value: Value) -> Result<Option<BTreeMap<String, String>>, AnyError> {
+    let env: BTreeMap<String, String> = serde_json::from_value(value)?;
+    Ok(Some(env).filter(|env| !env.is_empty()))
+}
+
+fn args_from_value(value: Value) -> Result<Option<Vec<String>>, AnyError> {
+    let args: Vec<String> = serde_json::from_value(value)?;
+    Ok(Some(args).filter(|args| !args.is_empty()))
+}
@@ -1,5 +1,9 @@
+pub mod control;
 pub mod engine;
+pub mod export;
 pub mod models;
+pub mod outputs;
 pub mod parser;
+pub mod schedule;
 pub mod schema;
 pub mod utils;
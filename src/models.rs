@@ -1,4 +1,5 @@
 use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
 
 use diesel::prelude::*;
 use serde_derive::{Deserialize, Serialize};
@@ -18,6 +19,12 @@ pub struct Engine {
     pub stopped_at: chrono::NaiveDateTime,
     pub task_process_status: String,
     pub event_process_status: String,
+    // Written by the task process on every loop, an engine whose task process stops writing it is lost
+    pub last_heartbeat_at: Option<chrono::NaiveDateTime>,
+    // Written by the event process on every loop, only engines writing it get a share of the events
+    pub event_heartbeat_at: Option<chrono::NaiveDateTime>,
+    // Set along with stop_signal when the engine should finish its running tasks before stopping
+    pub drain_signal: bool,
 }
 
 #[derive(Insertable, Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -32,6 +39,8 @@ pub enum EngineStatus {
     Starting,
     Running,
     Stopped,
+    // Missed its heartbeats, its tasks were taken over by another engine
+    Lost,
 }
 
 impl Display for EngineStatus {
@@ -40,6 +49,7 @@ impl Display for EngineStatus {
             EngineStatus::Starting => write!(f, "Starting"),
             EngineStatus::Running => write!(f, "Running"),
             EngineStatus::Stopped => write!(f, "Stopped"),
+            EngineStatus::Lost => write!(f, "Lost"),
         }
     }
 }
@@ -47,6 +57,7 @@ impl Display for EngineStatus {
 pub enum ProcessStatus {
     Running,
     Stopped,
+    Lost,
 }
 
 impl Display for ProcessStatus {
@@ -54,6 +65,48 @@ impl Display for ProcessStatus {
         match self {
             ProcessStatus::Running => write!(f, "Running"),
             ProcessStatus::Stopped => write!(f, "Stopped"),
+            ProcessStatus::Lost => write!(f, "Lost"),
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::workflows)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Workflow {
+    pub uid: i32,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    pub env: serde_json::Value,
+    pub args: serde_json::Value,
+    pub version: i32,
+    // Only the latest version of a workflow is active, older ones are kept for the runs started on them
+    pub active: bool,
+}
+
+#[derive(Insertable, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::workflows)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewWorkflow<'a> {
+    pub name: Option<&'a str>,
+    pub description: Option<&'a str>,
+    pub created_at: chrono::NaiveDateTime,
+    pub env: serde_json::Value,
+    pub args: serde_json::Value,
+    pub version: i32,
+}
+
+impl Default for NewWorkflow<'_> {
+    fn default() -> Self {
+        NewWorkflow {
+            name: None,
+            description: None,
+            created_at: chrono::Local::now().naive_local(),
+            env: serde_json::json!({}),
+            args: serde_json::json!([]),
+            version: 1,
         }
     }
 }
@@ -65,34 +118,100 @@ pub struct Event {
     pub uid: i32,
     pub name: Option<String>,
     pub description: Option<String>,
-    pub trigger: String,
+    pub trigger: Option<String>,
     pub status: String, // TODO: change to enum
     pub created_at: chrono::NaiveDateTime,
     pub triggered_at: Option<chrono::NaiveDateTime>,
     pub deleted_at: Option<chrono::NaiveDateTime>,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
+    pub workflow_uid: Option<i32>,
+    pub schedule: Option<String>,
+    pub timezone: Option<String>,
+    pub every_seconds: Option<i64>,
+    pub next_fire_at: Option<chrono::NaiveDateTime>,
+    pub mode: String,
+    pub cooldown_seconds: Option<i64>,
+    pub timeout_seconds: Option<i64>,
+    pub poll_interval_seconds: Option<i64>,
+    pub max_attempts: Option<i32>,
+    pub backoff: String,
+    pub attempts: i32,
+    pub next_poll_at: Option<chrono::NaiveDateTime>,
+    pub env: serde_json::Value,
+    pub args: serde_json::Value,
+    pub interpreter: Option<String>,
+    pub command: Option<String>,
+    pub run: Option<String>,
+    pub working_directory: Option<String>,
+    // The engine evaluating the event, another engine takes it over once the lease expires
+    pub owner_engine_uid: Option<i32>,
+    pub lease_expires_at: Option<chrono::NaiveDateTime>,
+}
+
+impl Event {
+    pub fn trigger_script(&self) -> Option<Script> {
+        Script::new(
+            self.trigger.clone(),
+            self.interpreter.clone(),
+            self.command.clone(),
+            self.run.clone(),
+            self.working_directory.clone(),
+        )
+    }
 }
 
 #[derive(Insertable, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[diesel(table_name = crate::schema::events)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct NewEvent<'a> {
+    pub workflow_uid: Option<i32>,
     pub name: Option<&'a str>,
     pub description: Option<&'a str>,
-    pub trigger: &'a str,
+    pub trigger: Option<&'a str>,
     pub status: String,
     pub created_at: chrono::NaiveDateTime,
+    pub schedule: Option<&'a str>,
+    pub timezone: Option<&'a str>,
+    pub every_seconds: Option<i64>,
+    pub mode: String,
+    pub cooldown_seconds: Option<i64>,
+    pub timeout_seconds: Option<i64>,
+    pub poll_interval_seconds: Option<i64>,
+    pub max_attempts: Option<i32>,
+    pub backoff: String,
+    pub env: serde_json::Value,
+    pub args: serde_json::Value,
+    pub interpreter: Option<String>,
+    pub command: Option<String>,
+    pub run: Option<String>,
+    pub working_directory: Option<String>,
 }
 
 impl Default for NewEvent<'_> {
     fn default() -> Self {
         NewEvent {
+            workflow_uid: None,
             name: None,
             description: None,
-            trigger: "",
+            trigger: None,
             status: EventStatus::Created.to_string(),
             created_at: chrono::Local::now().naive_local(),
+            schedule: None,
+            timezone: None,
+            every_seconds: None,
+            mode: EventMode::Once.to_string(),
+            cooldown_seconds: None,
+            timeout_seconds: None,
+            poll_interval_seconds: None,
+            max_attempts: None,
+            backoff: Backoff::Fixed.to_string(),
+            env: serde_json::json!({}),
+            args: serde_json::json!([]),
+            interpreter: None,
+            command: None,
+            run: None,
+            working_directory: None,
         }
     }
 }
@@ -101,31 +220,151 @@ impl Default for NewEvent<'_> {
 #[diesel(table_name = crate::schema::events)]
 pub struct LightEvent {
     pub uid: i32,
-    pub trigger: String,
+    pub trigger: Option<String>,
     pub status: String,
+    pub schedule: Option<String>,
+    pub timezone: Option<String>,
+    pub every_seconds: Option<i64>,
+    pub next_fire_at: Option<chrono::NaiveDateTime>,
+    pub mode: String,
+    pub timeout_seconds: Option<i64>,
+    pub poll_interval_seconds: Option<i64>,
+    pub max_attempts: Option<i32>,
+    pub backoff: String,
+    pub attempts: i32,
+    pub interpreter: Option<String>,
+    pub command: Option<String>,
+    pub run: Option<String>,
+    pub working_directory: Option<String>,
+}
+
+impl LightEvent {
+    // Time based events are fired by the engine instead of a trigger script
+    pub fn is_time_based(&self) -> bool {
+        self.schedule.is_some() || self.every_seconds.is_some()
+    }
+
+    pub fn trigger_script(&self) -> Option<Script> {
+        Script::new(
+            self.trigger.clone(),
+            self.interpreter.clone(),
+            self.command.clone(),
+            self.run.clone(),
+            self.working_directory.clone(),
+        )
+    }
 }
 
 impl fmt::Display for LightEvent {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         writeln!(f, "\tuid: {}", self.uid)?;
-        writeln!(f, "\ttrigger: {}", self.trigger)?;
+        if let Some(trigger_script) = self.trigger_script() {
+            writeln!(f, "\ttrigger: {}", trigger_script)?;
+        }
+        if let Some(schedule) = &self.schedule {
+            writeln!(
+                f,
+                "\tschedule: {} ({})",
+                schedule,
+                self.timezone.as_deref().unwrap_or("UTC")
+            )?;
+        }
+        if let Some(every_seconds) = self.every_seconds {
+            writeln!(f, "\tevery: {}s", every_seconds)?;
+        }
+        writeln!(f, "\tmode: {}", self.mode)?;
+        if let Some(timeout_seconds) = self.timeout_seconds {
+            writeln!(f, "\ttimeout: {}s", timeout_seconds)?;
+        }
+        if let Some(max_attempts) = self.max_attempts {
+            writeln!(f, "\tattempts: {}/{}", self.attempts, max_attempts)?;
+        }
         writeln!(f, "\tstatus: {}", self.status)?;
         Ok(())
     }
 }
 
+// Once events stop being polled after their first fire, recurring events keep being polled,
+// a cooldown can be set on recurring events to leave some time between fires
+#[derive(PartialEq)]
+pub enum EventMode {
+    Once,
+    Recurring,
+}
+
+impl Display for EventMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EventMode::Once => write!(f, "once"),
+            EventMode::Recurring => write!(f, "recurring"),
+        }
+    }
+}
+
+impl FromStr for EventMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "once" => Ok(EventMode::Once),
+            "recurring" => Ok(EventMode::Recurring),
+            _ => Err(anyhow::anyhow!(
+                "Unknown event mode '{}', expected once or recurring",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::event_runs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct EventRun {
+    pub uid: i32,
+    pub event_uid: i32,
+    pub status: String,
+    pub started_at: chrono::NaiveDateTime,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub payload: Option<String>,
+}
+
+#[derive(Insertable, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::event_runs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewEventRun<'a> {
+    pub event_uid: i32,
+    pub status: String,
+    pub stdout: Option<&'a str>,
+    pub stderr: Option<&'a str>,
+    pub payload: Option<&'a str>,
+}
+
 pub enum EventStatus {
     Created,
+    Scheduled,
+    Triggered,
     Succeeded,
     Retrying,
+    Paused,
+    Aborted,
+    TimedOut,
+    // Its trigger failed max_attempts times in a row, it is no longer polled
+    Exhausted,
 }
 
 impl Display for EventStatus {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             EventStatus::Created => write!(f, "Created"),
+            EventStatus::Scheduled => write!(f, "Scheduled"),
+            EventStatus::Triggered => write!(f, "Triggered"),
             EventStatus::Succeeded => write!(f, "Succeeded"),
             EventStatus::Retrying => write!(f, "Retrying"),
+            EventStatus::Paused => write!(f, "Paused"),
+            EventStatus::Aborted => write!(f, "Aborted"),
+            EventStatus::TimedOut => write!(f, "TimedOut"),
+            EventStatus::Exhausted => write!(f, "Exhausted"),
         }
     }
 }
@@ -138,15 +377,22 @@ pub struct Task {
     pub event_uid: i32,
     pub name: Option<String>,
     pub description: Option<String>,
-    pub path: String,
+    pub path: Option<String>,
     pub on_failure: Option<String>,
     pub status: String, // TODO: change to enum
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
     pub deleted_at: Option<chrono::NaiveDateTime>,
-    pub completed_at: Option<chrono::NaiveDateTime>,
-    pub stdout: Option<String>,
-    pub stderr: Option<String>,
+    pub timeout_seconds: Option<i64>,
+    pub retries: i32,
+    pub retry_delay_seconds: Option<i64>,
+    pub backoff: String,
+    pub env: serde_json::Value,
+    pub args: serde_json::Value,
+    pub interpreter: Option<String>,
+    pub command: Option<String>,
+    pub run: Option<String>,
+    pub working_directory: Option<String>,
 }
 
 #[derive(Insertable, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -156,11 +402,21 @@ pub struct NewTask {
     pub event_uid: i32,
     pub name: Option<String>,
     pub description: Option<String>,
-    pub path: String,
+    pub path: Option<String>,
     pub on_failure: Option<String>,
     pub status: String,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    pub timeout_seconds: Option<i64>,
+    pub retries: i32,
+    pub retry_delay_seconds: Option<i64>,
+    pub backoff: String,
+    pub env: serde_json::Value,
+    pub args: serde_json::Value,
+    pub interpreter: Option<String>,
+    pub command: Option<String>,
+    pub run: Option<String>,
+    pub working_directory: Option<String>,
 }
 
 impl Default for NewTask {
@@ -169,50 +425,239 @@ impl Default for NewTask {
             event_uid: 0,
             name: None,
             description: None,
-            path: "".to_string(),
+            path: None,
             on_failure: None,
             status: EventStatus::Created.to_string(),
             created_at: chrono::Local::now().naive_local(),
             updated_at: chrono::Local::now().naive_local(),
+            timeout_seconds: None,
+            retries: 0,
+            retry_delay_seconds: None,
+            backoff: Backoff::Fixed.to_string(),
+            env: serde_json::json!({}),
+            args: serde_json::json!([]),
+            interpreter: None,
+            command: None,
+            run: None,
+            working_directory: None,
         }
     }
 }
 
-#[derive(Queryable, Selectable, Serialize, Deserialize)]
-#[diesel(table_name = crate::schema::tasks)]
+impl Task {
+    pub fn script(&self) -> Option<Script> {
+        Script::new(
+            self.path.clone(),
+            self.interpreter.clone(),
+            self.command.clone(),
+            self.run.clone(),
+            self.working_directory.clone(),
+        )
+    }
+}
+
+// What a task or a trigger runs, a script file, an inline script or an executable.
+// Script files run from their own directory, the others from working_directory
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Script {
+    pub path: Option<String>,
+    pub interpreter: Option<String>,
+    pub command: Option<String>,
+    pub run: Option<String>,
+    pub working_directory: Option<String>,
+}
+
+impl Script {
+    // None when there is nothing to run
+    pub fn new(
+        path: Option<String>,
+        interpreter: Option<String>,
+        command: Option<String>,
+        run: Option<String>,
+        working_directory: Option<String>,
+    ) -> Option<Script> {
+        if path.is_none() && command.is_none() && run.is_none() {
+            return None;
+        }
+        Some(Script {
+            path,
+            interpreter,
+            command,
+            run,
+            working_directory,
+        })
+    }
+
+    pub fn from_path(path: &str) -> Script {
+        Script {
+            path: Some(path.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+impl Display for Script {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(command) = &self.command {
+            write!(f, "command: {}", command)?;
+        } else if let Some(path) = &self.path {
+            write!(f, "{}", path)?;
+        } else {
+            write!(f, "inline script")?;
+        }
+        if let Some(interpreter) = &self.interpreter {
+            write!(f, " ({})", interpreter)?;
+        }
+        Ok(())
+    }
+}
+
+// What goes through the queue, a single run of a task
+#[derive(Serialize, Deserialize)]
 pub struct LightTask {
     pub uid: i32,
-    pub path: String,
+    pub task_run_uid: i32,
+    pub run_uid: i32,
+    pub script: Script,
     pub on_failure: Option<String>,
+    pub timeout_seconds: Option<i64>,
+    pub retries: i32,
+    pub retry_delay_seconds: Option<i64>,
+    pub backoff: String,
+    // Output references in env values and args are interpolated right before the task runs
+    pub env: Vec<(String, String)>,
+    pub args: Vec<String>,
 }
 
 impl Display for LightTask {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         writeln!(f, "\tuid: {}", self.uid)?;
-        writeln!(f, "\tpath: {}", self.path)?;
+        writeln!(f, "\ttask_run_uid: {}", self.task_run_uid)?;
+        writeln!(f, "\trun_uid: {}", self.run_uid)?;
+        writeln!(f, "\tscript: {}", self.script)?;
         writeln!(
             f,
             "\ton_failure: {}",
             self.on_failure.as_ref().unwrap_or(&"None".to_string())
         )?;
+        if let Some(timeout_seconds) = self.timeout_seconds {
+            writeln!(f, "\ttimeout: {}s", timeout_seconds)?;
+        }
+        if self.retries > 0 {
+            writeln!(f, "\tretries: {} ({})", self.retries, self.backoff)?;
+        }
+        if !self.args.is_empty() {
+            writeln!(f, "\targs: {}", self.args.join(" "))?;
+        }
         Ok(())
     }
 }
 
+// How the delay between two attempts of a task grows, exponential doubles it after every attempt
+#[derive(PartialEq)]
+pub enum Backoff {
+    Fixed,
+    Exponential,
+}
+
+impl Display for Backoff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Backoff::Fixed => write!(f, "fixed"),
+            Backoff::Exponential => write!(f, "exponential"),
+        }
+    }
+}
+
+impl FromStr for Backoff {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Backoff::Fixed),
+            "exponential" => Ok(Backoff::Exponential),
+            _ => Err(anyhow::anyhow!(
+                "Unknown backoff '{}', expected fixed or exponential",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::task_runs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TaskRun {
+    pub uid: i32,
+    pub run_uid: Option<i32>,
+    pub task_uid: i32,
+    pub status: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+    pub started_at: Option<chrono::NaiveDateTime>,
+    pub completed_at: Option<chrono::NaiveDateTime>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub on_failure_status: Option<String>,
+    pub on_failure_stdout: Option<String>,
+    pub on_failure_stderr: Option<String>,
+    pub attempt: i32,
+    pub outputs: Option<serde_json::Value>,
+    // The engine that picked the task run up from the queue last, and when
+    pub engine_uid: Option<i32>,
+    pub picked_up_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Insertable, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::task_runs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewTaskRun {
+    pub run_uid: i32,
+    pub task_uid: i32,
+    pub status: String,
+}
+
+#[derive(Insertable, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::task_dependencies)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewTaskDependency {
+    pub task_uid: i32,
+    pub dependency_uid: i32,
+}
+
 pub enum TaskStatus {
+    // Waiting for the tasks it depends on
+    Waiting,
     Pending,
     Running,
     Completed,
     Failed,
+    Paused,
+    Aborted,
+    // One of the tasks it depends on didn't complete
+    Skipped,
+    // Killed after running longer than its timeout
+    TimedOut,
+    // Failed, waiting for its next attempt
+    Retrying,
+    // Killed by its engine shutting down, waiting to run again
+    Interrupted,
 }
 
 impl Display for TaskStatus {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
+            TaskStatus::Waiting => write!(f, "Waiting"),
             TaskStatus::Pending => write!(f, "Pending"),
             TaskStatus::Running => write!(f, "Running"),
             TaskStatus::Completed => write!(f, "Completed"),
             TaskStatus::Failed => write!(f, "Failed"),
+            TaskStatus::Paused => write!(f, "Paused"),
+            TaskStatus::Aborted => write!(f, "Aborted"),
+            TaskStatus::Skipped => write!(f, "Skipped"),
+            TaskStatus::TimedOut => write!(f, "TimedOut"),
+            TaskStatus::Retrying => write!(f, "Retrying"),
+            TaskStatus::Interrupted => write!(f, "Interrupted"),
         }
     }
 }
@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Error as AnyError};
+use std::ops::Range;
+
+// A reference to an output of another task of the run, `${{ tasks.<name>.outputs.<key> }}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputReference {
+    pub task_name: String,
+    pub key: String,
+    // Where the whole `${{ ... }}` expression is in the text
+    pub range: Range<usize>,
+}
+
+/// Finds every `${{ tasks.<name>.outputs.<key> }}` expression in the text,
+/// any other expression between `${{` and `}}` is an error
+pub fn find_output_references(text: &str) -> Result<Vec<OutputReference>, AnyError> {
+    let mut references = Vec::new();
+    let mut search_from = 0;
+    while let Some(offset) = text[search_from..].find("${{") {
+        let start = search_from + offset;
+        let end = match text[start..].find("}}") {
+            Some(offset) => start + offset + 2,
+            None => return Err(anyhow!("Unclosed expression in '{}'", text)),
+        };
+        let expression = text[start + 3..end - 2].trim();
+        match expression.split('.').collect::<Vec<&str>>().as_slice() {
+            ["tasks", task_name, "outputs", key] if !task_name.is_empty() && !key.is_empty() => {
+                references.push(OutputReference {
+                    task_name: task_name.to_string(),
+                    key: key.to_string(),
+                    range: start..end,
+                })
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unknown expression '{}', expected tasks.<name>.outputs.<key>",
+                    expression
+                ))
+            }
+        }
+        search_from = end;
+    }
+    Ok(references)
+}
+
+/// Replaces every output reference in the text with the value `resolve` gives for it
+pub fn interpolate_outputs<F>(text: &str, mut resolve: F) -> Result<String, AnyError>
+where
+    F: FnMut(&OutputReference) -> Result<String, AnyError>,
+{
+    let mut interpolated = String::new();
+    let mut copied_up_to = 0;
+    for reference in find_output_references(text)? {
+        interpolated.push_str(&text[copied_up_to..reference.range.start]);
+        interpolated.push_str(&resolve(&reference)?);
+        copied_up_to = reference.range.end;
+    }
+    interpolated.push_str(&text[copied_up_to..]);
+    Ok(interpolated)
+}
+
+/// Parses what a task wrote to its output file, one `key=value` per line,
+/// or `key<<DELIMITER` followed by the lines of the value and a line with the delimiter
+pub fn parse_output_file(contents: &str) -> Result<Vec<(String, String)>, AnyError> {
+    let mut outputs = Vec::new();
+    let mut lines = contents.lines();
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A value can contain `<<`, the line is only a heredoc if it comes before any `=`
+        let heredoc = match (line.find("<<"), line.find('=')) {
+            (Some(heredoc_at), Some(equals_at)) => heredoc_at < equals_at,
+            (heredoc_at, _) => heredoc_at.is_some(),
+        };
+        if let Some((key, delimiter)) = line.split_once("<<").filter(|_| heredoc) {
+            let mut value_lines = Vec::new();
+            loop {
+                match lines.next() {
+                    Some(value_line) if value_line == delimiter => break,
+                    Some(value_line) => value_lines.push(value_line),
+                    None => {
+                        return Err(anyhow!(
+                            "Output '{}' is missing its closing delimiter '{}'",
+                            key,
+                            delimiter
+                        ))
+                    }
+                }
+            }
+            outputs.push((key.trim().to_string(), value_lines.join("\n")));
+        } else if let Some((key, value)) = line.split_once('=') {
+            outputs.push((key.trim().to_string(), value.to_string()));
+        } else {
+            return Err(anyhow!(
+                "Invalid output line '{}', expected key=value",
+                line
+            ));
+        }
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_key_value_lines() {
+        let parsed = parse_output_file("version=1.2.3\n\n  name =build=42\n").unwrap();
+        assert_eq!(
+            parsed,
+            outputs(&[("version", "1.2.3"), ("name", "build=42")])
+        );
+    }
+
+    #[test]
+    fn parses_heredoc_values() {
+        let parsed = parse_output_file("notes<<EOF\nfirst line\nsecond=line\nEOF\nok=1").unwrap();
+        assert_eq!(
+            parsed,
+            outputs(&[("notes", "first line\nsecond=line"), ("ok", "1")])
+        );
+    }
+
+    #[test]
+    fn keeps_heredoc_markers_inside_values() {
+        let parsed = parse_output_file("url=http://x?a<<b").unwrap();
+        assert_eq!(parsed, outputs(&[("url", "http://x?a<<b")]));
+    }
+
+    #[test]
+    fn rejects_unclosed_heredocs_and_lines_without_a_key() {
+        assert!(parse_output_file("notes<<EOF\nfirst line").is_err());
+        assert!(parse_output_file("just some text").is_err());
+    }
+
+    #[test]
+    fn finds_output_references() {
+        let text = "v=${{ tasks.build.outputs.version }}-${{tasks.test.outputs.id}}";
+        let references = find_output_references(text).unwrap();
+        assert_eq!(
+            references,
+            vec![
+                OutputReference {
+                    task_name: "build".to_string(),
+                    key: "version".to_string(),
+                    range: 2..36,
+                },
+                OutputReference {
+                    task_name: "test".to_string(),
+                    key: "id".to_string(),
+                    range: 37..63,
+                },
+            ]
+        );
+        assert_eq!(
+            &text[references[0].range.clone()],
+            "${{ tasks.build.outputs.version }}"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_unclosed_expressions() {
+        assert!(find_output_references("${{ env.HOME }}").is_err());
+        assert!(find_output_references("${{ tasks..outputs.key }}").is_err());
+        assert!(find_output_references("${{ tasks.build.outputs.version").is_err());
+        assert!(find_output_references("no expressions").unwrap().is_empty());
+    }
+}
@@ -1,86 +1,1090 @@
-use crate::models::{NewEvent, NewTask};
-use crate::utils::{establish_pg_connection, insert_event_into_db, insert_event_tasks_into_db};
-use anyhow::{Error as AnyError, Ok, Result};
+use crate::models::{Backoff, EventMode, NewEvent, NewTask, NewWorkflow};
+use crate::outputs::find_output_references;
+use crate::schedule::{parse_cron_schedule, parse_every, parse_timezone};
+use crate::utils::{establish_pg_connection, insert_workflow_into_db};
+use anyhow::{anyhow, Error as AnyError, Ok, Result};
+use diesel::PgConnection;
+use schemars::JsonSchema;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
-use std::fs::File;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+// Version of the workflow file format, files without a version are read as the first one.
+// Changes to the format bump it, and files of older versions are migrated when they are parsed
+pub const WORKFLOW_FORMAT_VERSION: u32 = 1;
+// Path that reads the workflow from stdin instead of a file
+pub const STDIN_PATH: &str = "-";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorkflowFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl WorkflowFormat {
+    // Files are read as yaml unless their extension says otherwise, so is stdin
+    pub fn from_path(file_path: &str) -> Result<Self, AnyError> {
+        if file_path == STDIN_PATH {
+            return Ok(WorkflowFormat::Yaml);
+        }
+        match Path::new(file_path).extension().and_then(|e| e.to_str()) {
+            Some("yml") | Some("yaml") => Ok(WorkflowFormat::Yaml),
+            Some("json") => Ok(WorkflowFormat::Json),
+            Some("toml") => Ok(WorkflowFormat::Toml),
+            _ => Err(anyhow!(
+                "Can't tell the format of {} from its extension, set it with --format",
+                file_path
+            )),
+        }
+    }
+
+    pub fn deserialize(&self, source: &str) -> Result<Workflow, AnyError> {
+        let workflow = match self {
+            WorkflowFormat::Yaml => serde_yaml::from_str(source)?,
+            WorkflowFormat::Json => serde_json::from_str(source)?,
+            WorkflowFormat::Toml => toml::from_str(source)?,
+        };
+        Ok(workflow)
+    }
+
+    pub fn serialize(&self, workflow: &Workflow) -> Result<String, AnyError> {
+        // Unset fields are left out instead of being written as nulls
+        let value = without_nulls(serde_json::to_value(workflow)?);
+        let serialized = match self {
+            WorkflowFormat::Yaml => serde_yaml::to_string(&value)?,
+            WorkflowFormat::Json => serde_json::to_string_pretty(&value)? + "\n",
+            WorkflowFormat::Toml => toml::to_string(&value)?,
+        };
+        Ok(serialized)
+    }
+}
+
+impl Display for WorkflowFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkflowFormat::Yaml => write!(f, "yaml"),
+            WorkflowFormat::Json => write!(f, "json"),
+            WorkflowFormat::Toml => write!(f, "toml"),
+        }
+    }
+}
+
+impl FromStr for WorkflowFormat {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yaml" | "yml" => Ok(WorkflowFormat::Yaml),
+            "json" => Ok(WorkflowFormat::Json),
+            "toml" => Ok(WorkflowFormat::Toml),
+            _ => Err(anyhow!(
+                "Unknown format '{}', expected yaml, json or toml",
+                s
+            )),
+        }
+    }
+}
+
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k, without_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(without_nulls).collect()),
+        value => value,
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Workflow {
+    /// Version of the workflow file format, 1 if not set
+    #[schemars(range(min = 1))]
+    pub version: Option<u32>,
     pub name: Option<String>,
     pub description: Option<String>,
+    /// Environment variables inherited by every event and task of the workflow
+    pub env: Option<BTreeMap<String, String>>,
+    /// Arguments inherited by every event and task of the workflow
+    pub args: Option<Vec<String>>,
     pub events: Vec<ParsableEvent>,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// An event is either triggered by a script, a cron schedule or a fixed interval
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ParsableEvent {
     pub name: Option<String>,
     pub description: Option<String>,
-    pub trigger: String,
+    /// Script run to check if the event happened, relative to the workflow file
+    pub trigger: Option<String>,
+    /// Executable run as the trigger, along with its arguments
+    pub command: Option<String>,
+    /// Inline script run as the trigger
+    pub run: Option<String>,
+    /// What runs the trigger script, taken from its shebang line or executable bit by default
+    pub interpreter: Option<String>,
+    /// Cron schedule the event fires on
+    pub schedule: Option<String>,
+    /// Timezone of the schedule, UTC by default
+    pub timezone: Option<String>,
+    /// Interval the event fires at, such as `10m`
+    pub every: Option<String>,
+    /// once or recurring, script triggered events default to once and time based ones to recurring
+    pub mode: Option<String>,
+    /// Minimum time between two fires of a recurring event
+    pub cooldown: Option<String>,
+    /// How long the trigger script can run, such as `30s`, the engine wide default applies if not set
+    pub timeout: Option<String>,
+    /// How often the trigger script is run
+    pub poll_interval: Option<String>,
+    /// How many failed runs in a row it takes to give up on the event
+    #[schemars(range(min = 1))]
+    pub max_attempts: Option<u32>,
+    /// fixed or exponential, how the delay after a failed run of the trigger grows
+    pub backoff: Option<String>,
+    /// Environment variables of the trigger, inherited by the tasks of the event.
+    /// Variables set on a task override the inherited ones
+    pub env: Option<BTreeMap<String, String>>,
+    /// Arguments of the trigger, inherited by the tasks of the event.
+    /// Arguments set on a task replace the inherited ones
+    pub args: Option<Vec<String>>,
     pub tasks: Vec<ParsableTask>,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+impl ParsableEvent {
+    fn is_script_triggered(&self) -> bool {
+        self.trigger.is_some() || self.command.is_some() || self.run.is_some()
+    }
+
+    // Checks that exactly one kind of trigger is set and returns the interval in seconds, if any
+    fn validate_trigger(&self) -> Result<Option<i64>, AnyError> {
+        let event_name = self.name.as_deref().unwrap_or("unnamed");
+        let trigger_count = [
+            self.trigger.is_some(),
+            self.command.is_some(),
+            self.run.is_some(),
+            self.schedule.is_some(),
+            self.every.is_some(),
+        ]
+        .iter()
+        .filter(|is_set| **is_set)
+        .count();
+        if trigger_count != 1 {
+            return Err(anyhow!(
+                "Event '{}' needs exactly one of trigger, command, run, schedule or every",
+                event_name
+            ));
+        }
+        if self.interpreter.is_some() && self.trigger.is_none() && self.run.is_none() {
+            return Err(anyhow!(
+                "Event '{}' has an interpreter but no trigger script",
+                event_name
+            ));
+        }
+        if let Some(command) = &self.command {
+            validate_command(command)
+                .map_err(|e| anyhow!("Event '{}' has an invalid command: {}", event_name, e))?;
+        }
+        if let Some(interpreter) = &self.interpreter {
+            validate_command(interpreter)
+                .map_err(|e| anyhow!("Event '{}' has an invalid interpreter: {}", event_name, e))?;
+        }
+        if let Some(schedule) = &self.schedule {
+            parse_cron_schedule(schedule)?;
+        }
+        match (&self.timezone, &self.schedule) {
+            (Some(timezone), Some(_)) => {
+                parse_timezone(timezone)?;
+            }
+            (Some(_), None) => {
+                return Err(anyhow!(
+                    "Event '{}' has a timezone but no schedule",
+                    event_name
+                ))
+            }
+            _ => {}
+        }
+        self.every.as_deref().map(parse_every).transpose()
+    }
+
+    // Returns the mode of the event and its cooldown in seconds, if any
+    fn validate_mode(&self) -> Result<(EventMode, Option<i64>), AnyError> {
+        let mode = match &self.mode {
+            Some(mode) => mode.parse::<EventMode>()?,
+            None if self.is_script_triggered() => EventMode::Once,
+            None => EventMode::Recurring,
+        };
+        let cooldown_seconds = self.cooldown.as_deref().map(parse_every).transpose()?;
+        if cooldown_seconds.is_some() && mode != EventMode::Recurring {
+            return Err(anyhow!(
+                "Event '{}' has a cooldown but is not recurring",
+                self.name.as_deref().unwrap_or("unnamed")
+            ));
+        }
+        Ok((mode, cooldown_seconds))
+    }
+
+    // Polling settings only apply to events triggered by a script
+    fn validate_polling(&self) -> Result<(Option<i64>, Option<i32>, Backoff), AnyError> {
+        let has_polling_settings =
+            self.poll_interval.is_some() || self.max_attempts.is_some() || self.backoff.is_some();
+        if has_polling_settings && !self.is_script_triggered() {
+            return Err(anyhow!(
+                "Event '{}' has polling settings but no trigger script",
+                self.name.as_deref().unwrap_or("unnamed")
+            ));
+        }
+        let poll_interval_seconds = self.poll_interval.as_deref().map(parse_every).transpose()?;
+        let max_attempts = self.max_attempts.map(i32::try_from).transpose()?;
+        let backoff = self
+            .backoff
+            .as_deref()
+            .map(str::parse::<Backoff>)
+            .transpose()?
+            .unwrap_or(Backoff::Fixed);
+        Ok((poll_interval_seconds, max_attempts, backoff))
+    }
+
+    // Dependencies have to name a single task of the same event, and can't form a cycle
+    fn validate_dependencies(&self) -> Result<(), AnyError> {
+        let event_name = self.name.as_deref().unwrap_or("unnamed");
+        let mut dependencies: Vec<Vec<usize>> = Vec::new();
+        for t in &self.tasks {
+            let mut task_dependencies = Vec::new();
+            for dependency_name in t.depends_on.iter().flatten() {
+                let matching: Vec<usize> = self
+                    .tasks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, other)| other.name.as_ref() == Some(dependency_name))
+                    .map(|(index, _)| index)
+                    .collect();
+                match matching.as_slice() {
+                    [index] => task_dependencies.push(*index),
+                    [] => {
+                        return Err(anyhow!(
+                            "Event '{}' has no task named '{}' to depend on",
+                            event_name,
+                            dependency_name
+                        ))
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "Event '{}' has more than one task named '{}' to depend on",
+                            event_name,
+                            dependency_name
+                        ))
+                    }
+                }
+            }
+            dependencies.push(task_dependencies);
+        }
+
+        // Depth first search, a task that is reached again while it is still being visited closes a cycle
+        fn visit(
+            index: usize,
+            dependencies: &[Vec<usize>],
+            states: &mut [VisitState],
+            path: &mut Vec<usize>,
+        ) -> Option<Vec<usize>> {
+            match states[index] {
+                VisitState::Done => return None,
+                VisitState::Visiting => {
+                    let start = path.iter().position(|i| *i == index).unwrap();
+                    let mut cycle = path[start..].to_vec();
+                    cycle.push(index);
+                    return Some(cycle);
+                }
+                VisitState::New => {}
+            }
+            states[index] = VisitState::Visiting;
+            path.push(index);
+            for dependency in &dependencies[index] {
+                if let Some(cycle) = visit(*dependency, dependencies, states, path) {
+                    return Some(cycle);
+                }
+            }
+            path.pop();
+            states[index] = VisitState::Done;
+            None
+        }
+
+        let mut states = vec![VisitState::New; self.tasks.len()];
+        for index in 0..self.tasks.len() {
+            if let Some(cycle) = visit(index, &dependencies, &mut states, &mut Vec::new()) {
+                let names: Vec<&str> = cycle
+                    .iter()
+                    .map(|i| self.tasks[*i].name.as_deref().unwrap_or("unnamed"))
+                    .collect();
+                return Err(anyhow!(
+                    "Event '{}' has a dependency cycle: {}",
+                    event_name,
+                    names.join(" -> ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Outputs can only be used from tasks that are done by the time the task starts,
+    // the ones it depends on directly or not
+    fn validate_output_references(&self) -> Result<(), AnyError> {
+        if has_output_references(&self.env, &self.args)? {
+            return Err(anyhow!(
+                "Event '{}' uses task outputs, they can only be used in the env and args of tasks",
+                self.name.as_deref().unwrap_or("unnamed")
+            ));
+        }
+        for t in &self.tasks {
+            let task_name = t.name.as_deref().unwrap_or("unnamed");
+            let texts = t
+                .env
+                .iter()
+                .flat_map(|env| env.values())
+                .chain(t.args.iter().flatten());
+            for text in texts {
+                for reference in find_output_references(text)? {
+                    if !self
+                        .dependency_names(t)
+                        .contains(reference.task_name.as_str())
+                    {
+                        return Err(anyhow!(
+                            "Task '{}' uses outputs of '{}' but doesn't depend on it",
+                            task_name,
+                            reference.task_name
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Names of the tasks the task depends on, directly or not
+    fn dependency_names<'a>(&'a self, task: &'a ParsableTask) -> HashSet<&'a str> {
+        let mut names = HashSet::new();
+        let mut to_visit: Vec<&str> = task
+            .depends_on
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        while let Some(name) = to_visit.pop() {
+            if !names.insert(name) {
+                continue;
+            }
+            for other in &self.tasks {
+                if other.name.as_deref() == Some(name) {
+                    to_visit.extend(other.depends_on.iter().flatten().map(String::as_str));
+                }
+            }
+        }
+        names
+    }
+}
+
+impl ParsableTask {
+    fn validate_script(&self) -> Result<(), AnyError> {
+        let task_name = self.name.as_deref().unwrap_or("unnamed");
+        let script_count = [
+            self.path.is_some(),
+            self.command.is_some(),
+            self.run.is_some(),
+        ]
+        .iter()
+        .filter(|is_set| **is_set)
+        .count();
+        if script_count != 1 {
+            return Err(anyhow!(
+                "Task '{}' needs exactly one of path, command or run",
+                task_name
+            ));
+        }
+        if self.interpreter.is_some() && self.command.is_some() {
+            return Err(anyhow!(
+                "Task '{}' has an interpreter but runs a command",
+                task_name
+            ));
+        }
+        if let Some(command) = &self.command {
+            validate_command(command)
+                .map_err(|e| anyhow!("Task '{}' has an invalid command: {}", task_name, e))?;
+        }
+        if let Some(interpreter) = &self.interpreter {
+            validate_command(interpreter)
+                .map_err(|e| anyhow!("Task '{}' has an invalid interpreter: {}", task_name, e))?;
+        }
+        Ok(())
+    }
+
+    // Returns the timeout, retries, retry delay and backoff of the task
+    fn validate_settings(&self) -> Result<(Option<i64>, i32, Option<i64>, Backoff), AnyError> {
+        let timeout_seconds = self.timeout.as_deref().map(parse_every).transpose()?;
+        let retries = self.retries.unwrap_or(0).try_into()?;
+        let retry_delay_seconds = self.retry_delay.as_deref().map(parse_every).transpose()?;
+        let backoff = self
+            .backoff
+            .as_deref()
+            .map(str::parse::<Backoff>)
+            .transpose()?
+            .unwrap_or(Backoff::Fixed);
+        Ok((timeout_seconds, retries, retry_delay_seconds, backoff))
+    }
+}
+
+// Scripts are run directly when they are executable, otherwise their interpreter has to read them
+fn validate_script_file(path: &Path) -> Result<(), AnyError> {
+    let metadata =
+        fs::metadata(path).map_err(|e| anyhow!("Can't find {}, {}", path.display(), e))?;
+    if !metadata.is_file() {
+        return Err(anyhow!("{} is not a file", path.display()));
+    }
+    let is_executable = metadata.permissions().mode() & 0o111 != 0;
+    if !is_executable && File::open(path).is_err() {
+        return Err(anyhow!(
+            "{} is neither executable nor readable",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+// Commands and interpreters are split into the executable and its arguments like a shell would
+fn validate_command(command: &str) -> Result<(), AnyError> {
+    if shell_words::split(command)?.is_empty() {
+        return Err(anyhow!("it is empty"));
+    }
+    Ok(())
+}
+
+fn has_output_references(
+    env: &Option<BTreeMap<String, String>>,
+    args: &Option<Vec<String>>,
+) -> Result<bool, AnyError> {
+    for text in env
+        .iter()
+        .flat_map(|env| env.values())
+        .chain(args.iter().flatten())
+    {
+        if !find_output_references(text)?.is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[derive(Clone, Copy)]
+enum VisitState {
+    New,
+    Visiting,
+    Done,
+}
+
+/// A task runs either a script file, an executable with `command` or an inline script with `run`
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ParsableTask {
     pub name: Option<String>,
     pub description: Option<String>,
-    pub path: String,
+    /// Script run by the task, relative to the workflow file
+    pub path: Option<String>,
+    /// Executable run by the task, along with its arguments
+    pub command: Option<String>,
+    /// Inline script run by the task
+    pub run: Option<String>,
+    /// What runs the script, taken from its shebang line or executable bit by default, bash for inline scripts
+    pub interpreter: Option<String>,
+    /// Script run once the task has failed, relative to the workflow file
     pub on_failure: Option<String>,
+    /// Names of the tasks of the same event that have to complete before this one starts
+    pub depends_on: Option<Vec<String>>,
+    /// How long the task can run, such as `10m`, the engine wide default applies if not set
+    pub timeout: Option<String>,
+    /// How many more times a failed task is attempted
+    pub retries: Option<u32>,
+    /// How long to wait before attempting a failed task again
+    pub retry_delay: Option<String>,
+    /// fixed or exponential, exponential doubles the delay after every attempt
+    pub backoff: Option<String>,
+    /// Environment variables of the task, on top of the inherited ones. They can use outputs of
+    /// the tasks it depends on through `${{ tasks.<name>.outputs.<key> }}`
+    pub env: Option<BTreeMap<String, String>>,
+    /// Arguments of the task, replacing the inherited ones. They can use outputs of the tasks it
+    /// depends on through `${{ tasks.<name>.outputs.<key> }}`
+    pub args: Option<Vec<String>>,
 }
 
-fn parse_yaml_file(file_path: &str) -> Result<Workflow, AnyError> {
-    let file = File::open(file_path)?;
-    let workflow: Workflow = serde_yaml::from_reader(file)?;
-    println!("{:?}", workflow);
-    Ok(workflow)
+/// Text of a workflow file along with its format, used to point problems found after parsing at
+/// where they are written
+pub struct WorkflowSource {
+    pub text: String,
+    pub format: WorkflowFormat,
+}
+
+// Error a probe stops the parser with once it reaches its node
+const PROBE_REACHED: &str = "probe reached its node";
+
+// Step from a node of the workflow file to one of its children
+#[derive(Clone, Copy)]
+enum Step<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+// Follows steps down the workflow file while the parser reads it and fails on the node they lead
+// to, the parser then tells where that node starts
+struct Probe<'a> {
+    steps: &'a [Step<'a>],
+}
+
+impl<'a> Probe<'a> {
+    // Fails on the node the steps lead to, reading the rest of the file goes on otherwise
+    fn reached<E: de::Error>(&self) -> Result<(), E> {
+        self.steps
+            .first()
+            .map(|_| ())
+            .ok_or_else(|| E::custom(PROBE_REACHED))
+    }
 }
 
-pub fn process_yaml_file(yaml_file_path: String) -> Result<(), AnyError> {
-    let workflow = parse_yaml_file(&yaml_file_path)?;
+impl<'de, 'a> DeserializeSeed<'de> for Probe<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for Probe<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("any value")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        self.reached()?;
+        while let Some(key) = map.next_key::<String>()? {
+            match self.steps[0] {
+                Step::Key(step) if step == key => map.next_value_seed(Probe {
+                    steps: &self.steps[1..],
+                })?,
+                _ => map.next_value::<IgnoredAny>().map(|_| ())?,
+            }
+        }
+        self.reached()
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        self.reached()?;
+        let mut index = 0;
+        loop {
+            let next = match self.steps[0] {
+                Step::Index(step) if step == index => seq
+                    .next_element_seed(Probe {
+                        steps: &self.steps[1..],
+                    })?
+                    .is_some(),
+                _ => seq.next_element::<IgnoredAny>()?.is_some(),
+            };
+            if !next {
+                return self.reached();
+            }
+            index += 1;
+        }
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<(), E> {
+        self.reached()
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<(), E> {
+        self.reached()
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<(), E> {
+        self.reached()
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<(), E> {
+        self.reached()
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<(), E> {
+        self.reached()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        self.reached()
+    }
+}
+
+impl WorkflowSource {
+    // Line and column, both starting at 1, of the node the steps lead to, as told by the parser
+    fn locate(&self, steps: &[Step]) -> Option<(usize, usize)> {
+        let probe = Probe { steps };
+        let reached = |message: String| message.contains(PROBE_REACHED);
+        match self.format {
+            WorkflowFormat::Yaml => {
+                let error = probe
+                    .deserialize(serde_yaml::Deserializer::from_str(&self.text))
+                    .err()?;
+                let location = error.location().filter(|_| reached(error.to_string()))?;
+                Some((location.line(), location.column()))
+            }
+            WorkflowFormat::Json => {
+                let mut deserializer = serde_json::Deserializer::from_str(&self.text);
+                let error = probe.deserialize(&mut deserializer).err()?;
+                reached(error.to_string()).then(|| (error.line(), error.column()))
+            }
+            WorkflowFormat::Toml => {
+                let error = probe
+                    .deserialize(toml::Deserializer::new(&self.text))
+                    .err()?;
+                let span = error.span().filter(|_| reached(error.to_string()))?;
+                let before = &self.text[..span.start];
+                let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+                Some((
+                    before.matches('\n').count() + 1,
+                    before[line_start..].chars().count() + 1,
+                ))
+            }
+        }
+    }
+}
+
+// Runs every check on a parsed workflow and returns the problems found, along with where they are
+// when the source of the workflow is known
+fn validate_workflow(
+    workflow: &Workflow,
+    source: Option<&WorkflowSource>,
+    workflow_path: &Path,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    // Problems are placed at the node the steps lead to, looked up only once one is found as
+    // every lookup parses the whole file again
+    let mut check = |steps: &[Step], result: Result<(), AnyError>| {
+        if let Err(e) = result {
+            problems.push(match source.and_then(|source| source.locate(steps)) {
+                Some((line, column)) => format!("line {} column {}: {}", line, column, e),
+                None => e.to_string(),
+            });
+        }
+    };
+
+    check(
+        &[],
+        match workflow.name {
+            Some(_) => Ok(()),
+            None => Err(anyhow!(
+                "Workflow needs a name, adding a workflow with the same name adds a new version of it"
+            )),
+        },
+    );
+    check(
+        &[],
+        has_output_references(&workflow.env, &workflow.args).and_then(|has_references| {
+            match has_references {
+                true => Err(anyhow!(
+                    "Workflow uses task outputs, they can only be used in the env and args of tasks"
+                )),
+                false => Ok(()),
+            }
+        }),
+    );
+
+    for (event_index, e) in workflow.events.iter().enumerate() {
+        let event_steps = [Step::Key("events"), Step::Index(event_index)];
+        check(&event_steps, e.validate_trigger().map(|_| ()));
+        check(&event_steps, e.validate_mode().map(|_| ()));
+        check(&event_steps, e.validate_polling().map(|_| ()));
+        check(&event_steps, e.validate_dependencies());
+        check(&event_steps, e.validate_output_references());
+        check(
+            &event_steps,
+            e.timeout
+                .as_deref()
+                .map(parse_every)
+                .transpose()
+                .map(|_| ()),
+        );
+        if let Some(trigger) = &e.trigger {
+            let [events, event] = event_steps;
+            check(
+                &[events, event, Step::Key("trigger")],
+                validate_script_file(&workflow_path.join(trigger)),
+            );
+        }
+
+        let mut task_names = HashSet::new();
+        for (task_index, t) in e.tasks.iter().enumerate() {
+            let [events, event] = event_steps;
+            let task_steps = [events, event, Step::Key("tasks"), Step::Index(task_index)];
+            if let Some(task_name) = &t.name {
+                if !task_names.insert(task_name) {
+                    check(
+                        &task_steps,
+                        Err(anyhow!(
+                            "Event '{}' has more than one task named '{}'",
+                            e.name.as_deref().unwrap_or("unnamed"),
+                            task_name
+                        )),
+                    );
+                }
+            }
+            check(&task_steps, t.validate_script());
+            check(&task_steps, t.validate_settings().map(|_| ()));
+            for (key, script_path) in [("path", &t.path), ("on_failure", &t.on_failure)] {
+                if let Some(script_path) = script_path {
+                    let [events, event, tasks, task] = task_steps;
+                    check(
+                        &[events, event, tasks, task, Step::Key(key)],
+                        validate_script_file(&workflow_path.join(script_path)),
+                    );
+                }
+            }
+        }
+    }
+    problems
+}
+
+// Errors of the parsers, such as unknown fields, already say where they are
+fn parse_workflow_file(
+    file_path: &str,
+    format: Option<WorkflowFormat>,
+) -> Result<(Workflow, WorkflowSource), AnyError> {
+    let format = match format {
+        Some(format) => format,
+        None => WorkflowFormat::from_path(file_path)?,
+    };
+    let source = if file_path == STDIN_PATH {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        source
+    } else {
+        fs::read_to_string(file_path)?
+    };
+    let workflow = format
+        .deserialize(&source)
+        .and_then(migrate_workflow)
+        .map_err(|e| anyhow!("{} is not valid, {}", file_path, e))?;
+    Ok((
+        workflow,
+        WorkflowSource {
+            text: source,
+            format,
+        },
+    ))
+}
+
+// Brings a workflow of an older format version up to the current one
+fn migrate_workflow(workflow: Workflow) -> Result<Workflow, AnyError> {
+    match workflow.version.unwrap_or(1) {
+        WORKFLOW_FORMAT_VERSION => Ok(workflow),
+        version => Err(anyhow!(
+            "Workflow format version {} is not supported, the latest version is {}",
+            version,
+            WORKFLOW_FORMAT_VERSION
+        )),
+    }
+}
+
+/// JSON Schema of the workflow file format, for editors to complete and check workflow files
+pub fn workflow_schema() -> Result<String, AnyError> {
+    let schema = schemars::schema_for!(Workflow);
+    Ok(serde_json::to_string_pretty(&schema)?)
+}
+
+// Scripts are relative to the directory of the workflow file, or to the current directory
+// for workflows read from stdin
+fn workflow_directory(file_path: &str) -> PathBuf {
+    let current_dir = env::current_dir().expect("Failed to get current directory");
+    if file_path == STDIN_PATH {
+        return current_dir;
+    }
+    current_dir.join(Path::new(file_path).parent().unwrap())
+}
+
+/// Checks a workflow file without adding it, printing every problem found
+pub fn validate_workflow_file(
+    file_path: &str,
+    format: Option<WorkflowFormat>,
+) -> Result<(), AnyError> {
+    let (workflow, source) = parse_workflow_file(file_path, format)?;
+    let problems = validate_workflow(&workflow, Some(&source), &workflow_directory(file_path));
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        return Err(anyhow!("{} has {} problem(s)", file_path, problems.len()));
+    }
+    Ok(())
+}
+
+pub fn process_workflow_file(
+    file_path: &str,
+    format: Option<WorkflowFormat>,
+) -> Result<(), AnyError> {
+    let (workflow, source) = parse_workflow_file(file_path, format)?;
+    println!("{:?}", workflow);
     println!("name: {:?}", workflow.name);
     println!("description: {:?}", workflow.description);
 
-    let workflow_root_path = std::path::Path::new(&yaml_file_path)
-        .parent()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_owned();
+    let (workflow_uid, version) = add_workflow(
+        &mut establish_pg_connection(),
+        &workflow,
+        Some(&source),
+        &workflow_directory(file_path),
+    )?;
+    println!(
+        "Added version {} of workflow '{}' with uid: {}",
+        version,
+        workflow.name.as_deref().unwrap_or("unnamed"),
+        workflow_uid
+    );
 
-    let workflow_path = env::current_dir()
-        .expect("Failed to get current directory")
-        .join(workflow_root_path);
+    Ok(())
+}
+
+/// Validates a parsed workflow and inserts it as the next version of the workflow with its name,
+/// returns the uid and the version of the new workflow. Scripts are relative to `workflow_path`,
+/// problems are placed in `source` when the workflow was read from one.
+pub fn add_workflow(
+    conn: &mut PgConnection,
+    workflow: &Workflow,
+    source: Option<&WorkflowSource>,
+    workflow_path: &Path,
+) -> Result<(i32, i32), AnyError> {
+    // Nothing is inserted unless the whole workflow is valid
+    let problems = validate_workflow(workflow, source, workflow_path);
+    if !problems.is_empty() {
+        return Err(anyhow!("Workflow is not valid:\n{}", problems.join("\n")));
+    }
 
-    for e in workflow.events {
+    let trigger_paths: Vec<Option<String>> = workflow
+        .events
+        .iter()
+        .map(|e| {
+            e.trigger
+                .as_ref()
+                .map(|trigger| workflow_path.join(trigger).to_str().unwrap().to_string())
+        })
+        .collect();
+
+    let working_directory = workflow_path.to_str().unwrap().to_string();
+    let mut new_events = Vec::new();
+    for (e, trigger_path) in workflow.events.iter().zip(trigger_paths.iter()) {
+        let every_seconds = e.validate_trigger()?;
+        let (mode, cooldown_seconds) = e.validate_mode()?;
+        let (poll_interval_seconds, max_attempts, backoff) = e.validate_polling()?;
         let new_event = NewEvent {
             name: e.name.as_deref(),
             description: e.description.as_deref(),
-            trigger: &workflow_path.join(e.trigger).to_str().unwrap().to_string(),
+            trigger: trigger_path.as_deref(),
+            interpreter: e.interpreter.clone(),
+            command: e.command.clone(),
+            run: e.run.clone(),
+            working_directory: Some(working_directory.clone()),
+            schedule: e.schedule.as_deref(),
+            timezone: e.timezone.as_deref(),
+            every_seconds,
+            mode: mode.to_string(),
+            cooldown_seconds,
+            timeout_seconds: e.timeout.as_deref().map(parse_every).transpose()?,
+            poll_interval_seconds,
+            max_attempts,
+            backoff: backoff.to_string(),
+            env: serde_json::to_value(e.env.clone().unwrap_or_default())?,
+            args: serde_json::to_value(e.args.clone().unwrap_or_default())?,
             ..Default::default()
         };
-        // let new_event = ParsableEvent {
-        //     name: e.name,
-        //     description: e.description,
-        //     trigger: workflow_path.join(e.trigger).to_str().unwrap().to_string(),
-        //     tasks: tasks.clone(),
-        // };
-        let mut conn = establish_pg_connection();
-        let event_uid = insert_event_into_db(&mut conn, new_event)?;
 
         let mut tasks = Vec::new();
-        for t in e.tasks {
+        for t in &e.tasks {
+            let (timeout_seconds, retries, retry_delay_seconds, task_backoff) =
+                t.validate_settings()?;
+            // event_uid is set once the event is inserted
             let task = NewTask {
-                event_uid,
-                name: t.name,
-                description: t.description,
-                path: workflow_path.join(t.path).to_str().unwrap().to_string(),
-                on_failure: t.on_failure,
+                name: t.name.clone(),
+                description: t.description.clone(),
+                path: t
+                    .path
+                    .as_ref()
+                    .map(|p| workflow_path.join(p).to_str().unwrap().to_string()),
+                interpreter: t.interpreter.clone(),
+                command: t.command.clone(),
+                run: t.run.clone(),
+                working_directory: Some(working_directory.clone()),
+                on_failure: t
+                    .on_failure
+                    .as_ref()
+                    .map(|f| workflow_path.join(f).to_str().unwrap().to_string()),
+                timeout_seconds,
+                retries,
+                retry_delay_seconds,
+                backoff: task_backoff.to_string(),
+                env: serde_json::to_value(t.env.clone().unwrap_or_default())?,
+                args: serde_json::to_value(t.args.clone().unwrap_or_default())?,
                 ..Default::default()
             };
-            tasks.push(task);
+            tasks.push((task, t.depends_on.clone().unwrap_or_default()));
         }
-        insert_event_tasks_into_db(&mut conn, tasks)?;
+        new_events.push((new_event, tasks));
     }
 
-    Ok(())
+    let new_workflow = NewWorkflow {
+        name: workflow.name.as_deref(),
+        description: workflow.description.as_deref(),
+        env: serde_json::to_value(workflow.env.clone().unwrap_or_default())?,
+        args: serde_json::to_value(workflow.args.clone().unwrap_or_default())?,
+        ..Default::default()
+    };
+    insert_workflow_into_db(conn, new_workflow, new_events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(name: &str, depends_on: &[&str]) -> ParsableTask {
+        ParsableTask {
+            name: Some(name.to_string()),
+            depends_on: Some(depends_on.iter().map(|d| d.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    fn event(tasks: Vec<ParsableTask>) -> ParsableEvent {
+        ParsableEvent {
+            name: Some("deploy".to_string()),
+            tasks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validates_commands_with_quoted_arguments() {
+        assert!(validate_command("printf '%s' \"a b\" c").is_ok());
+        assert!(validate_command("echo 'unclosed").is_err());
+        assert!(validate_command("  ").is_err());
+    }
+
+    #[test]
+    fn accepts_dependencies_without_cycles() {
+        let event = event(vec![
+            task("build", &[]),
+            task("test", &["build"]),
+            task("lint", &["build"]),
+            task("release", &["test", "lint"]),
+        ]);
+        assert!(event.validate_dependencies().is_ok());
+    }
+
+    #[test]
+    fn rejects_dependency_cycles() {
+        let event = event(vec![
+            task("build", &["release"]),
+            task("test", &["build"]),
+            task("release", &["test"]),
+        ]);
+        let error = event.validate_dependencies().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Event 'deploy' has a dependency cycle: build -> release -> test -> build"
+        );
+    }
+
+    #[test]
+    fn rejects_tasks_depending_on_themselves() {
+        let error = event(vec![task("build", &["build"])])
+            .validate_dependencies()
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Event 'deploy' has a dependency cycle: build -> build"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_ambiguous_dependencies() {
+        assert!(event(vec![task("test", &["build"])])
+            .validate_dependencies()
+            .is_err());
+        assert!(event(vec![
+            task("build", &[]),
+            task("build", &[]),
+            task("test", &["build"])
+        ])
+        .validate_dependencies()
+        .is_err());
+    }
+
+    fn problems(text: &str, format: WorkflowFormat) -> Vec<String> {
+        let workflow = format.deserialize(text).unwrap();
+        let source = WorkflowSource {
+            text: text.to_string(),
+            format,
+        };
+        validate_workflow(&workflow, Some(&source), Path::new("."))
+    }
+
+    #[test]
+    fn locates_problems_at_their_event_and_task() {
+        let text = "\
+name: locations
+events:
+  - name: first
+    every: 1m
+    tasks:
+      - name: a
+        command: echo a
+  - name: a
+    every: 0s
+    tasks:
+      - command: echo b
+        backoff: sideways
+";
+        assert_eq!(
+            problems(text, WorkflowFormat::Yaml),
+            [
+                "line 8 column 5: Duration '0s' has to be at least one second",
+                "line 11 column 9: Unknown backoff 'sideways', expected fixed or exponential",
+            ]
+        );
+    }
+
+    #[test]
+    fn locates_problems_in_toml_and_json() {
+        let toml = "\
+name = \"locations\"
+
+[[events]]
+name = \"a\"
+every = \"0s\"
+tasks = []
+";
+        assert_eq!(
+            problems(toml, WorkflowFormat::Toml),
+            ["line 3 column 1: Duration '0s' has to be at least one second"]
+        );
+        let json = "{\"name\": \"locations\", \"events\": [{\"name\": \"a\", \"every\": \"0s\", \"tasks\": []}]}";
+        assert_eq!(
+            problems(json, WorkflowFormat::Json),
+            ["line 1 column 34: Duration '0s' has to be at least one second"]
+        );
+    }
 }
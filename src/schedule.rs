@@ -0,0 +1,154 @@
+use crate::models::Backoff;
+use anyhow::{anyhow, Error as AnyError};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use rand::Rng;
+use std::str::FromStr;
+
+// Exponential backoff stops growing past an hour
+const MAX_BACKOFF_DELAY_SECONDS: i64 = 60 * 60;
+
+// Accepts the usual five field cron syntax, as well as the six or seven field syntax with seconds
+pub fn parse_cron_schedule(schedule: &str) -> Result<Schedule, AnyError> {
+    let expression = if schedule.split_whitespace().count() == 5 {
+        format!("0 {}", schedule)
+    } else {
+        schedule.to_string()
+    };
+    Schedule::from_str(&expression)
+        .map_err(|e| anyhow!("Invalid cron schedule '{}': {}", schedule, e))
+}
+
+pub fn parse_timezone(timezone: &str) -> Result<Tz, AnyError> {
+    timezone
+        .parse::<Tz>()
+        .map_err(|e| anyhow!("Invalid timezone '{}': {}", timezone, e))
+}
+
+// Parses durations such as `30s`, `5m` or `1h 30m` into seconds
+pub fn parse_every(every: &str) -> Result<i64, AnyError> {
+    let duration = humantime::parse_duration(every)
+        .map_err(|e| anyhow!("Invalid duration '{}': {}", every, e))?;
+    if duration.as_secs() == 0 {
+        return Err(anyhow!(
+            "Duration '{}' has to be at least one second",
+            every
+        ));
+    }
+    Ok(duration.as_secs() as i64)
+}
+
+/// Returns the first time after `after` the event is due, all times are in UTC.
+/// Events without a schedule or an interval are never due.
+pub fn next_fire_time(
+    schedule: Option<&str>,
+    timezone: Option<&str>,
+    every_seconds: Option<i64>,
+    after: NaiveDateTime,
+) -> Result<Option<NaiveDateTime>, AnyError> {
+    if let Some(schedule) = schedule {
+        let cron_schedule = parse_cron_schedule(schedule)?;
+        let timezone = parse_timezone(timezone.unwrap_or("UTC"))?;
+        let after = Utc.from_utc_datetime(&after).with_timezone(&timezone);
+        return Ok(cron_schedule
+            .after(&after)
+            .next()
+            .map(|next| next.naive_utc()));
+    }
+    if let Some(every_seconds) = every_seconds {
+        return Ok(Some(after + chrono::Duration::seconds(every_seconds)));
+    }
+    Ok(None)
+}
+
+/// Delay before the attempt following `failed_attempt`, attempts are counted from 1.
+/// Exponential backoff doubles the delay after every attempt and picks a random delay
+/// between half and all of it, so retries spread out.
+pub fn backoff_delay_seconds(
+    backoff: &Backoff,
+    base_delay_seconds: i64,
+    failed_attempt: i32,
+) -> i64 {
+    match backoff {
+        Backoff::Fixed => base_delay_seconds,
+        Backoff::Exponential => {
+            let doubled = base_delay_seconds
+                .saturating_mul(1_i64 << (failed_attempt - 1).clamp(0, 32))
+                .min(MAX_BACKOFF_DELAY_SECONDS);
+            rand::thread_rng().gen_range(doubled / 2..=doubled)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_five_field_cron_schedules_from_the_minute() {
+        let schedule = parse_cron_schedule("30 2 * * *").unwrap();
+        let after = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let next = schedule.after(&after).next().unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2023, 9, 1, 2, 30, 0).unwrap());
+    }
+
+    #[test]
+    fn accepts_cron_schedules_with_seconds() {
+        let schedule = parse_cron_schedule("15 30 2 * * *").unwrap();
+        let after = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let next = schedule.after(&after).next().unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2023, 9, 1, 2, 30, 15).unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_cron_schedules() {
+        assert!(parse_cron_schedule("every day").is_err());
+        assert!(parse_cron_schedule("61 * * * *").is_err());
+    }
+
+    #[test]
+    fn parses_durations_into_seconds() {
+        assert_eq!(parse_every("30s").unwrap(), 30);
+        assert_eq!(parse_every("5m").unwrap(), 300);
+        assert_eq!(parse_every("1h 30m").unwrap(), 5400);
+    }
+
+    #[test]
+    fn rejects_invalid_and_sub_second_durations() {
+        assert!(parse_every("soon").is_err());
+        assert!(parse_every("0s").is_err());
+        assert!(parse_every("500ms").is_err());
+    }
+
+    #[test]
+    fn fixed_backoff_keeps_the_base_delay() {
+        for failed_attempt in 1..=5 {
+            assert_eq!(
+                backoff_delay_seconds(&Backoff::Fixed, 10, failed_attempt),
+                10
+            );
+        }
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_with_jitter() {
+        for failed_attempt in 1..=4 {
+            let doubled = 10 << (failed_attempt - 1);
+            let delay = backoff_delay_seconds(&Backoff::Exponential, 10, failed_attempt);
+            assert!((doubled / 2..=doubled).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn exponential_backoff_is_capped() {
+        for failed_attempt in [20, 40, i32::MAX] {
+            let delay = backoff_delay_seconds(&Backoff::Exponential, 10, failed_attempt);
+            assert!(
+                (MAX_BACKOFF_DELAY_SECONDS / 2..=MAX_BACKOFF_DELAY_SECONDS).contains(&delay),
+                "{}",
+                delay
+            );
+        }
+    }
+}
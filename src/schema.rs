@@ -11,6 +11,21 @@ diesel::table! {
         stopped_at -> Timestamp,
         task_process_status -> Varchar,
         event_process_status -> Varchar,
+        last_heartbeat_at -> Nullable<Timestamp>,
+        event_heartbeat_at -> Nullable<Timestamp>,
+        drain_signal -> Bool,
+    }
+}
+
+diesel::table! {
+    event_runs (uid) {
+        uid -> Int4,
+        event_uid -> Int4,
+        status -> Varchar,
+        started_at -> Timestamp,
+        stdout -> Nullable<Text>,
+        stderr -> Nullable<Text>,
+        payload -> Nullable<Text>,
     }
 }
 
@@ -19,13 +34,34 @@ diesel::table! {
         uid -> Int4,
         name -> Nullable<Varchar>,
         description -> Nullable<Varchar>,
-        trigger -> Varchar,
+        trigger -> Nullable<Varchar>,
         status -> Varchar,
         created_at -> Timestamp,
         triggered_at -> Nullable<Timestamp>,
         deleted_at -> Nullable<Timestamp>,
         stdout -> Nullable<Text>,
         stderr -> Nullable<Text>,
+        workflow_uid -> Nullable<Int4>,
+        schedule -> Nullable<Varchar>,
+        timezone -> Nullable<Varchar>,
+        every_seconds -> Nullable<Int8>,
+        next_fire_at -> Nullable<Timestamp>,
+        mode -> Varchar,
+        cooldown_seconds -> Nullable<Int8>,
+        timeout_seconds -> Nullable<Int8>,
+        poll_interval_seconds -> Nullable<Int8>,
+        max_attempts -> Nullable<Int4>,
+        backoff -> Varchar,
+        attempts -> Int4,
+        next_poll_at -> Nullable<Timestamp>,
+        env -> Jsonb,
+        args -> Jsonb,
+        interpreter -> Nullable<Varchar>,
+        command -> Nullable<Varchar>,
+        run -> Nullable<Text>,
+        working_directory -> Nullable<Varchar>,
+        owner_engine_uid -> Nullable<Int4>,
+        lease_expires_at -> Nullable<Timestamp>,
     }
 }
 
@@ -35,22 +71,82 @@ diesel::table! {
         event_uid -> Int4,
         name -> Nullable<Varchar>,
         description -> Nullable<Varchar>,
-        path -> Varchar,
+        path -> Nullable<Varchar>,
         on_failure -> Nullable<Varchar>,
         status -> Varchar,
         created_at -> Timestamp,
         updated_at -> Timestamp,
         deleted_at -> Nullable<Timestamp>,
+        timeout_seconds -> Nullable<Int8>,
+        retries -> Int4,
+        retry_delay_seconds -> Nullable<Int8>,
+        backoff -> Varchar,
+        env -> Jsonb,
+        args -> Jsonb,
+        interpreter -> Nullable<Varchar>,
+        command -> Nullable<Varchar>,
+        run -> Nullable<Text>,
+        working_directory -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    task_dependencies (task_uid, dependency_uid) {
+        task_uid -> Int4,
+        dependency_uid -> Int4,
+    }
+}
+
+diesel::table! {
+    task_runs (uid) {
+        uid -> Int4,
+        run_uid -> Nullable<Int4>,
+        task_uid -> Int4,
+        status -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        started_at -> Nullable<Timestamp>,
         completed_at -> Nullable<Timestamp>,
         stdout -> Nullable<Text>,
         stderr -> Nullable<Text>,
+        on_failure_status -> Nullable<Varchar>,
+        on_failure_stdout -> Nullable<Text>,
+        on_failure_stderr -> Nullable<Text>,
+        attempt -> Int4,
+        outputs -> Nullable<Jsonb>,
+        engine_uid -> Nullable<Int4>,
+        picked_up_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    workflows (uid) {
+        uid -> Int4,
+        name -> Nullable<Varchar>,
+        description -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
+        env -> Jsonb,
+        args -> Jsonb,
+        version -> Int4,
+        active -> Bool,
     }
 }
 
+diesel::joinable!(event_runs -> events (event_uid));
+diesel::joinable!(events -> engines (owner_engine_uid));
+diesel::joinable!(events -> workflows (workflow_uid));
+diesel::joinable!(task_runs -> engines (engine_uid));
+diesel::joinable!(task_runs -> event_runs (run_uid));
+diesel::joinable!(task_runs -> tasks (task_uid));
 diesel::joinable!(tasks -> events (event_uid));
 
 diesel::allow_tables_to_appear_in_same_query!(
     engines,
+    event_runs,
     events,
+    task_dependencies,
+    task_runs,
     tasks,
+    workflows,
 );
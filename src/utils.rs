@@ -1,17 +1,29 @@
-use anyhow::{Error as AnyError, Result};
-use bincode::serialize;
+use anyhow::{anyhow, Error as AnyError, Result};
+use bincode::{deserialize, serialize};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use dotenv::dotenv;
 use redis::Commands;
+use std::collections::HashMap;
 use std::env;
 
-use crate::models::{LightTask, NewEvent, NewTask};
+use crate::models::{LightTask, NewEvent, NewTask, NewTaskDependency, NewWorkflow};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use tracing::info;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 pub const QUEUE_NAME: &str = "tasks";
+// Paused tasks are parked here until they are continued or aborted
+pub const PAUSED_QUEUE_NAME: &str = "paused_tasks";
+// Sorted set of tasks waiting for their next attempt, scored by the unix time they are due at
+pub const DELAYED_QUEUE_NAME: &str = "delayed_tasks";
+// Tasks that can't be decoded are set aside here instead of being requeued over and over
+pub const DEAD_QUEUE_NAME: &str = "dead_tasks";
+
+// Tasks being executed by an engine are kept in its processing queue until they are acknowledged
+pub fn processing_queue_name(engine_uid: i32) -> String {
+    format!("{}:processing:{}", QUEUE_NAME, engine_uid)
+}
 
 pub fn create_redis_connection() -> Result<redis::Connection, redis::RedisError> {
     dotenv().ok();
@@ -38,35 +50,261 @@ pub fn push_tasks_to_queue(tasks: Vec<LightTask>) -> Result<(), AnyError> {
     }
     let mut redis_con = redis_result.unwrap();
     for light_task in tasks {
-        let serialized_task: Vec<u8> = serialize(&light_task).unwrap();
-        redis_con.rpush(QUEUE_NAME, serialized_task)?;
+        push_task_to_queue(&mut redis_con, QUEUE_NAME, &light_task)?;
     }
     Ok(())
 }
 
-// TODO, insert workflow into db, instead of the two functions bellow
-pub fn insert_event_into_db(conn: &mut PgConnection, new_event: NewEvent) -> Result<i32, AnyError> {
-    use crate::schema::events::dsl::*;
-    let event_uid = diesel::insert_into(events)
-        .values(&new_event)
-        .returning(uid)
-        .get_result::<i32>(conn)?;
-    Ok(event_uid)
+// Tasks are queued in the bincode layout of LightTask, which has no version. Entries queued by
+// an engine with another layout can't be decoded, they are skipped by the lookups here.
+fn decode_queued_task(serialized_task: &[u8], queue_name: &str) -> Option<LightTask> {
+    match deserialize(serialized_task) {
+        Ok(light_task) => Some(light_task),
+        Err(e) => {
+            eprintln!(
+                "Skipping a task of {} that can't be decoded {}",
+                queue_name, e
+            );
+            None
+        }
+    }
 }
 
-pub fn insert_event_tasks_into_db(
-    conn: &mut PgConnection,
-    new_tasks: Vec<NewTask>,
+// Removes a task run from a redis list and returns it, if it was found in the list
+pub fn remove_task_from_queue(
+    redis_con: &mut redis::Connection,
+    queue_name: &str,
+    task_run_uid: i32,
+) -> Result<Option<LightTask>, AnyError> {
+    let serialized_tasks: Vec<Vec<u8>> = redis_con.lrange(queue_name, 0, -1)?;
+    for serialized_task in serialized_tasks {
+        let Some(light_task) = decode_queued_task(&serialized_task, queue_name) else {
+            continue;
+        };
+        if light_task.task_run_uid == task_run_uid {
+            redis_con.lrem::<_, _, ()>(queue_name, 1, serialized_task)?;
+            return Ok(Some(light_task));
+        }
+    }
+    Ok(None)
+}
+
+// Removes a task from the processing queue of the engine once its outcome is in the database
+pub fn acknowledge_task(
+    redis_con: &mut redis::Connection,
+    engine_uid: i32,
+    serialized_task: &[u8],
+) -> Result<(), AnyError> {
+    redis_con.lrem::<_, _, ()>(processing_queue_name(engine_uid), 1, serialized_task)?;
+    Ok(())
+}
+
+// Moves a task out of the processing queue of the engine to the dead tasks
+pub fn bury_task(
+    redis_con: &mut redis::Connection,
+    engine_uid: i32,
+    serialized_task: &[u8],
 ) -> Result<(), AnyError> {
-    for new_task in new_tasks {
-        diesel::insert_into(crate::schema::tasks::table)
-            .values(new_task)
-            .execute(conn)?; // TODO: check if this is the correct way to do it
+    move_processing_task(
+        redis_con,
+        engine_uid,
+        serialized_task,
+        "RPUSH",
+        DEAD_QUEUE_NAME,
+    )
+}
+
+// Moves a task out of the processing queue of the engine back to the front of the queue
+pub fn release_task(
+    redis_con: &mut redis::Connection,
+    engine_uid: i32,
+    serialized_task: &[u8],
+) -> Result<(), AnyError> {
+    move_processing_task(redis_con, engine_uid, serialized_task, "LPUSH", QUEUE_NAME)
+}
+
+// Takes a task out of the processing queue of the engine and pushes it to another queue in one
+// step, so a task is never in both or in neither. A task that was requeued in the meantime, by a
+// restart of the engine, is no longer in the processing queue and isn't pushed again.
+const MOVE_PROCESSING_TASK_SCRIPT: &str = r"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 1 then
+    redis.call(ARGV[2], KEYS[2], ARGV[1])
+end
+";
+
+fn move_processing_task(
+    redis_con: &mut redis::Connection,
+    engine_uid: i32,
+    serialized_task: &[u8],
+    push_command: &str,
+    queue_name: &str,
+) -> Result<(), AnyError> {
+    redis::Script::new(MOVE_PROCESSING_TASK_SCRIPT)
+        .key(processing_queue_name(engine_uid))
+        .key(queue_name)
+        .arg(serialized_task)
+        .arg(push_command)
+        .invoke::<()>(redis_con)?;
+    Ok(())
+}
+
+// Moves every task left in the processing queue of an engine back to the front of the queue,
+// returns how many tasks were moved
+pub fn requeue_processing_queue(
+    redis_con: &mut redis::Connection,
+    engine_uid: i32,
+) -> Result<usize, AnyError> {
+    let processing_queue = processing_queue_name(engine_uid);
+    let mut requeued = 0;
+    loop {
+        let moved: Option<Vec<u8>> = redis_con.lmove(
+            &processing_queue,
+            QUEUE_NAME,
+            redis::Direction::Right,
+            redis::Direction::Left,
+        )?;
+        if moved.is_none() {
+            return Ok(requeued);
+        }
+        requeued += 1;
     }
+}
+
+pub fn push_task_to_delayed_queue(
+    redis_con: &mut redis::Connection,
+    light_task: &LightTask,
+    due_at: chrono::NaiveDateTime,
+) -> Result<(), AnyError> {
+    let serialized_task: Vec<u8> = serialize(light_task)?;
+    redis_con.zadd::<_, _, _, ()>(
+        DELAYED_QUEUE_NAME,
+        serialized_task,
+        due_at.and_utc().timestamp(),
+    )?;
+    Ok(())
+}
+
+// Moves the delayed tasks that are due to the back of the queue in one step, so engines moving
+// them at the same time never queue a task twice or lose one in between
+const MOVE_DUE_DELAYED_TASKS_SCRIPT: &str = r"
+local due_tasks = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+for _, task in ipairs(due_tasks) do
+    redis.call('ZREM', KEYS[1], task)
+    redis.call('RPUSH', KEYS[2], task)
+end
+return #due_tasks
+";
+
+// Moves the delayed tasks that are due to the queue, returns how many were moved
+pub fn move_due_delayed_tasks(redis_con: &mut redis::Connection) -> Result<usize, AnyError> {
+    let now = chrono::Utc::now().timestamp();
+    let moved: usize = redis::Script::new(MOVE_DUE_DELAYED_TASKS_SCRIPT)
+        .key(DELAYED_QUEUE_NAME)
+        .key(QUEUE_NAME)
+        .arg(now)
+        .invoke(redis_con)?;
+    Ok(moved)
+}
+
+// Removes a task run from the delayed tasks and returns it, if it was waiting for its next attempt
+pub fn remove_task_from_delayed_queue(
+    redis_con: &mut redis::Connection,
+    task_run_uid: i32,
+) -> Result<Option<LightTask>, AnyError> {
+    let serialized_tasks: Vec<Vec<u8>> = redis_con.zrange(DELAYED_QUEUE_NAME, 0, -1)?;
+    for serialized_task in serialized_tasks {
+        let Some(light_task) = decode_queued_task(&serialized_task, DELAYED_QUEUE_NAME) else {
+            continue;
+        };
+        if light_task.task_run_uid == task_run_uid {
+            redis_con.zrem::<_, _, ()>(DELAYED_QUEUE_NAME, serialized_task)?;
+            return Ok(Some(light_task));
+        }
+    }
+    Ok(None)
+}
 
+pub fn push_task_to_queue(
+    redis_con: &mut redis::Connection,
+    queue_name: &str,
+    light_task: &LightTask,
+) -> Result<(), AnyError> {
+    let serialized_task: Vec<u8> = serialize(light_task)?;
+    redis_con.rpush::<_, _, ()>(queue_name, serialized_task)?;
     Ok(())
 }
 
+// A task along with the names of the tasks of its event it depends on
+pub type TaskWithDependencies = (NewTask, Vec<String>);
+
+// Inserts a workflow with all of its events and tasks, nothing is inserted if any of the inserts fail.
+// A workflow with the name of an existing one is inserted as its next version, and replaces the
+// active version. Returns the uid and the version of the workflow.
+pub fn insert_workflow_into_db(
+    conn: &mut PgConnection,
+    mut new_workflow: NewWorkflow,
+    new_events: Vec<(NewEvent, Vec<TaskWithDependencies>)>,
+) -> Result<(i32, i32), AnyError> {
+    use crate::schema::workflows::dsl::*;
+
+    conn.transaction(|conn| {
+        if let Some(workflow_name) = new_workflow.name {
+            let latest_version: Option<i32> = workflows
+                .filter(name.eq(workflow_name))
+                .select(diesel::dsl::max(version))
+                .first(conn)?;
+            new_workflow.version = latest_version.unwrap_or(0) + 1;
+            // Runs already started on the previous version finish on it, new fires use this one
+            diesel::update(workflows)
+                .filter(name.eq(workflow_name))
+                .filter(active.eq(true))
+                .set(active.eq(false))
+                .execute(conn)?;
+        }
+        let (workflow_uid, workflow_version) = diesel::insert_into(workflows)
+            .values(&new_workflow)
+            .returning((uid, version))
+            .get_result::<(i32, i32)>(conn)?;
+
+        for (mut new_event, new_tasks) in new_events {
+            new_event.workflow_uid = Some(workflow_uid);
+            let event_uid = diesel::insert_into(crate::schema::events::table)
+                .values(&new_event)
+                .returning(crate::schema::events::uid)
+                .get_result::<i32>(conn)?;
+
+            let mut task_uids_by_name: HashMap<String, i32> = HashMap::new();
+            let mut task_dependency_names = Vec::new();
+            for (mut new_task, dependency_names) in new_tasks {
+                new_task.event_uid = event_uid;
+                let task_uid = diesel::insert_into(crate::schema::tasks::table)
+                    .values(&new_task)
+                    .returning(crate::schema::tasks::uid)
+                    .get_result::<i32>(conn)?;
+                if let Some(task_name) = new_task.name {
+                    task_uids_by_name.insert(task_name, task_uid);
+                }
+                task_dependency_names.push((task_uid, dependency_names));
+            }
+
+            for (task_uid, dependency_names) in task_dependency_names {
+                for dependency_name in dependency_names {
+                    let dependency_uid = task_uids_by_name
+                        .get(&dependency_name)
+                        .ok_or_else(|| anyhow!("Unknown dependency '{}'", dependency_name))?;
+                    diesel::insert_into(crate::schema::task_dependencies::table)
+                        .values(NewTaskDependency {
+                            task_uid,
+                            dependency_uid: *dependency_uid,
+                        })
+                        .execute(conn)?;
+                }
+            }
+        }
+        Ok((workflow_uid, workflow_version))
+    })
+}
+
 pub fn run_migrations() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     info!("Running Database migrations...");
     let mut conn = establish_pg_connection();
@@ -3,6 +3,7 @@ set -e
 weather_data=$(curl -s "http://wttr.in/Melbourne?format=j1")
 chance_of_rain=$(echo "$weather_data"  | jq -r ".weather[0].hourly[0].chanceofsunshine")
 if [[ $chance_of_rain -gt 90 ]]; then
+    echo "WORKFLOW_PAYLOAD={\"chance_of_rain\": $chance_of_rain}"
     exit 0
 else
     exit 1